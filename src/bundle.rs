@@ -0,0 +1,104 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+const MAGIC: &[u8; 8] = b"damned\0\x01";
+const TRAILER: u64 = 16;
+
+/// Copy the running executable to `out` with the script appended.
+///
+/// The script is followed by its length as a little-endian `u64` and [`MAGIC`].
+pub fn create(script: &[u8], out: &Path) -> io::Result<()> {
+    fs::copy(std::env::current_exe()?, out)?;
+    append(script, &mut OpenOptions::new().append(true).open(out)?)
+}
+
+fn append(script: &[u8], out: &mut impl Write) -> io::Result<()> {
+    out.write_all(script)?;
+    out.write_all(&(script.len() as u64).to_le_bytes())?;
+    out.write_all(MAGIC)
+}
+
+/// Get the script embedded in the running executable, if any.
+pub fn embedded() -> io::Result<Option<Vec<u8>>> {
+    read(&std::env::current_exe()?)
+}
+
+fn read(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    read_from(&mut File::open(path)?)
+}
+
+fn read_from(f: &mut (impl Read + Seek)) -> io::Result<Option<Vec<u8>>> {
+    let size = f.seek(SeekFrom::End(0))?;
+    if size < TRAILER {
+        return Ok(None);
+    }
+    let mut trailer = [0; TRAILER as usize];
+    f.seek(SeekFrom::Start(size - TRAILER))?;
+    f.read_exact(&mut trailer)?;
+    let (len, magic) = trailer.split_at(8);
+    if magic != MAGIC {
+        return Ok(None);
+    }
+    let len = u64::from_le_bytes(len.try_into().unwrap());
+    let start = (size - TRAILER)
+        .checked_sub(len)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bundle is truncated"))?;
+    let mut script = vec![0; len as usize];
+    f.seek(SeekFrom::Start(start))?;
+    f.read_exact(&mut script)?;
+    Ok(Some(script))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn bundle(exe: &[u8], script: &[u8]) -> Vec<u8> {
+        let mut buf = exe.to_vec();
+        append(script, &mut buf).unwrap();
+        buf
+    }
+
+    fn read(buf: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        read_from(&mut Cursor::new(buf))
+    }
+
+    #[test]
+    fn round_trip() {
+        for (exe, script) in [
+            (&b"\x7fELF..."[..], &b"1 2 + ."[..]),
+            (b"", b""),
+            (b"x", b""),
+        ] {
+            let buf = bundle(exe, script);
+            assert_eq!(buf.len(), exe.len() + script.len() + TRAILER as usize);
+            assert_eq!(read(&buf).unwrap().unwrap(), script);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        // no script appended
+        assert!(read(b"").unwrap().is_none());
+        assert!(read(b"\x7fELF...").unwrap().is_none());
+        assert!(read(&[0; 64]).unwrap().is_none());
+        // the trailer is cut short
+        let buf = bundle(b"\x7fELF...", b"script");
+        for n in 0..buf.len() {
+            if let Ok(Some(x)) = read(&buf[..n]) {
+                panic!("{x:?} read from {n} bytes");
+            }
+        }
+        // the length is longer than the file
+        let mut buf = bundle(b"", b"script");
+        buf[6] = 7;
+        assert!(read(&buf).is_err());
+        let mut buf = bundle(b"", b"");
+        buf[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(read(&buf).is_err());
+    }
+}
@@ -0,0 +1,35 @@
+#![forbid(unused_must_use)]
+
+pub mod bundle;
+pub mod script;
+
+use crossterm::{
+    execute,
+    terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use std::io;
+
+fn enable_tui() {
+    let _ = execute!(io::stdout(), EnterAlternateScreen);
+    let _ = terminal::enable_raw_mode();
+}
+
+fn disable_tui() {
+    let _ = terminal::disable_raw_mode();
+    let _ = execute!(io::stdout(), LeaveAlternateScreen);
+}
+
+/// Run the script loaded in a VM inside the terminal.
+pub fn run_tui<F>(mut vm: F)
+where
+    F: FnMut(&[u8]) -> script::Result<()>,
+{
+    enable_tui();
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        disable_tui();
+        (hook)(info);
+    }));
+    (vm)(b"").unwrap();
+    disable_tui();
+}
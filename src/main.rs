@@ -1,37 +1,44 @@
 #![forbid(unused_must_use)]
 
-mod script;
-
-use crossterm::{
-    execute,
-    terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
-};
-use std::io;
-
-fn enable_tui() {
-    let _ = execute!(io::stdout(), EnterAlternateScreen);
-    let _ = terminal::enable_raw_mode();
-}
-
-fn disable_tui() {
-    let _ = terminal::disable_raw_mode();
-    let _ = execute!(io::stdout(), LeaveAlternateScreen);
-}
+use damned::script;
 
 fn main() {
     let mut args = std::env::args();
     let _ = args.next();
+    if let Some(script) = damned::bundle::embedded().unwrap() {
+        let mut vm = script::create_root_vm(args);
+        vm(&script).unwrap();
+        return damned::run_tui(vm);
+    }
     let script = args.next().unwrap();
-    let script = std::fs::read(&script).unwrap();
-    let mut vm = script::create_root_vm(args);
-
-    enable_tui();
-    let hook = std::panic::take_hook();
-    std::panic::set_hook(Box::new(move |info| {
-        disable_tui();
-        (hook)(info);
-    }));
-    (vm)(&script).unwrap();
-    (vm)(b"").unwrap();
-    disable_tui();
+    if script == "bundle" {
+        let input = args.next().unwrap();
+        assert_eq!(
+            args.next().as_deref(),
+            Some("-o"),
+            "usage: damned bundle <script> -o <out>"
+        );
+        let output = args.next().unwrap();
+        let source = std::fs::read(&input).unwrap();
+        damned::bundle::create(&source, output.as_ref()).unwrap();
+    } else if script == "build" {
+        let input = args.next().unwrap();
+        assert_eq!(
+            args.next().as_deref(),
+            Some("-o"),
+            "usage: damned build <script> -o <out.rs>"
+        );
+        let output = args.next().unwrap();
+        let source = std::fs::read(&input).unwrap();
+        let out = script::aot::translate(&source, &input).unwrap();
+        std::fs::write(output, out).unwrap();
+    } else if script == "--image" {
+        let image = std::fs::read(args.next().unwrap()).unwrap();
+        damned::run_tui(script::load_root_vm(&image, args).unwrap());
+    } else {
+        let script = std::fs::read(&script).unwrap();
+        let mut vm = script::create_root_vm(args);
+        vm(&script).unwrap();
+        damned::run_tui(vm);
+    }
 }
@@ -0,0 +1,664 @@
+use super::{Compiler, Dictionary, Op, Root, Stack, Sym, Word, encode, next_word};
+use core::{cell::Cell, fmt::Write};
+use num::BigInt;
+use std::rc::Rc;
+
+pub use super::{Int, Object, Result};
+
+/// A translated colon definition.
+pub struct Native {
+    pub name: &'static str,
+    pub f: fn(&Ctx) -> Result<()>,
+    /// The words the definition calls or takes the execution token of.
+    pub refs: &'static [Ref],
+    /// Literals that can't be written as Rust, created when the definition is defined.
+    pub lits: &'static [Lit],
+}
+
+/// How a translated definition refers to a word.
+pub enum Ref {
+    /// A word defined by the host, by its path.
+    Native(&'static str),
+    /// A word defined by the script, by the name it has where the definition is defined.
+    Name(&'static str),
+}
+
+pub enum Lit {
+    /// An integer in decimal.
+    Int(&'static str),
+    /// An object encoded like `@encode` does.
+    Obj(&'static [u8]),
+    /// The execution token of a word in [`Native::refs`].
+    Token(usize),
+}
+
+enum Value {
+    Int(Int),
+    Obj(Object),
+}
+
+/// The words, literals and stacks of a translated definition.
+pub struct Ctx {
+    comp: Compiler,
+    calls: Box<[Box<[Word]>]>,
+    lits: Box<[Value]>,
+    int: Rc<Stack<Int>>,
+    obj: Rc<Stack<Object>>,
+    float: Rc<Stack<f64>>,
+}
+
+impl Ctx {
+    /// Call the `i`th word.
+    pub fn call(&self, i: usize) -> Result<()> {
+        self.calls[i].iter().try_for_each(|x| (x)())
+    }
+
+    /// Push the `i`th literal.
+    pub fn lit(&self, i: usize) -> Result<()> {
+        match &self.lits[i] {
+            Value::Int(x) => self.int.push(x.clone()),
+            Value::Obj(x) => self.obj.push(x.clone()),
+        }
+    }
+
+    /// Pop the result of a condition.
+    pub fn test(&self) -> Result<bool> {
+        Ok(self.int.pop()? != Int::ZERO)
+    }
+
+    pub fn push_int(&self, x: Int) -> Result<()> {
+        self.int.push(x)
+    }
+
+    pub fn pop_int(&self) -> Result<Int> {
+        self.int.pop()
+    }
+
+    pub fn push_obj(&self, x: Object) -> Result<()> {
+        self.obj.push(x)
+    }
+
+    pub fn pop_obj(&self) -> Result<Object> {
+        self.obj.pop()
+    }
+
+    pub fn push_float(&self, x: f64) -> Result<()> {
+        self.float.push(x)
+    }
+
+    /// Rearrange the integer stack, but only if it has at least `n` values.
+    pub fn shuffle_int<F>(&self, name: &str, n: usize, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut Vec<Int>, usize),
+    {
+        self.int.shuffle(name, n, f)
+    }
+
+    /// Rearrange the object stack, but only if it has at least `n` values.
+    pub fn shuffle_obj<F>(&self, name: &str, n: usize, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut Vec<Object>, usize),
+    {
+        self.obj.shuffle(name, n, f)
+    }
+
+    /// Run a function, restoring the stacks and touched cells if it fails.
+    pub fn transaction<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        self.comp.transaction(f)
+    }
+}
+
+/// Create a VM that runs a translated script.
+pub fn run<A>(words: &'static [Native], source: &str, args: A) -> impl FnMut(&[u8]) -> Result<()>
+where
+    A: IntoIterator<Item = String>,
+{
+    let root = Root::new();
+    let (c, d) = (root.comp.clone(), root.dictionary.clone());
+    let (int, obj, float) = (root.int.clone(), root.obj.clone(), root.float.clone());
+    root.dictionary.define(
+        "!aot",
+        root.comp.with(move || {
+            let w = usize::try_from(int.pop()?)?;
+            let w = words.get(w).ok_or("translated word doesn't exist")?;
+            let refs = w
+                .refs
+                .iter()
+                .map(|x| resolve(&d, x))
+                .collect::<Result<Vec<_>>>()?;
+            let lits = w
+                .lits
+                .iter()
+                .map(|x| {
+                    Ok(match x {
+                        Lit::Int(x) => Value::Int(x.parse::<BigInt>()?.into()),
+                        Lit::Obj(x) => Value::Obj(encode::decode(x)?),
+                        Lit::Token(i) => {
+                            let w = refs.get(*i).ok_or("translated word doesn't exist")?;
+                            Value::Obj(c.token(w)?)
+                        }
+                    })
+                })
+                .collect::<Result<_>>()?;
+            let ctx = Ctx {
+                comp: c.clone(),
+                calls: refs.iter().map(|x| c.body(x)).collect(),
+                lits,
+                int: int.clone(),
+                obj: obj.clone(),
+                float: float.clone(),
+            };
+            let (f, c2) = (w.f, c.clone());
+            let word = c.with(move || c2.aux_frame(|| (f)(&ctx)));
+            c.log(":", w.name, core::slice::from_ref(&word), |_| {
+                Err("translated words can't be saved in an image".into())
+            });
+            d.define(w.name, word);
+            Ok(())
+        }),
+    );
+    root.push_source(source.into());
+    root.run(args)
+}
+
+fn resolve(dict: &Dictionary, r: &Ref) -> Result<Word> {
+    Ok(match r {
+        Ref::Native(path) => dict
+            .native(path)
+            .ok_or_else(|| format!("native word {path:?} doesn't exist"))?,
+        Ref::Name(name) => dict
+            .get(name)
+            .ok_or_else(|| format!("undefined word {name:?}"))?,
+    })
+}
+
+struct Token<'a> {
+    word: String,
+    span: core::ops::Range<usize>,
+    source: &'a [u8],
+}
+
+/// A word called by translated code, as it will be looked up.
+#[derive(PartialEq)]
+enum Target {
+    Native(Rc<str>),
+    Name(Box<str>),
+}
+
+struct Def {
+    name: String,
+    code: String,
+    refs: Vec<Target>,
+    lits: Vec<String>,
+}
+
+fn tokenize(source: &[u8]) -> Result<Vec<Token<'_>>> {
+    let mut tokens = vec![];
+    let mut pos = 0;
+    loop {
+        while source.get(pos).is_some_and(|x| x.is_ascii_whitespace()) {
+            pos += 1;
+        }
+        let mut it = source[pos..].iter().copied();
+        let Some(word) = next_word(|| it.next())? else {
+            return Ok(tokens);
+        };
+        let span = pos..pos + word.len();
+        pos = span.end;
+        tokens.push(Token { word, span, source });
+    }
+}
+
+/// Translate a script to the source of a Rust program.
+///
+/// Colon definitions are compiled like the interpreter would, without running the script,
+/// and their operations are written as Rust that only calls back into the VM for words the
+/// translator doesn't know. Translated definitions are replaced by `N !aot`, so they are
+/// defined at the same point. Definitions that can only be compiled while the script runs
+/// are left to the interpreter, like everything outside definitions.
+pub fn translate(source: &[u8], name: &str) -> Result<String> {
+    let mut t = Translator::new();
+    let tokens = tokenize(source)?;
+    let mut defs = vec![];
+    let mut rest = String::new();
+    let mut copied = 0;
+    let mut i = 0;
+    while i < tokens.len() {
+        let start = &tokens[i];
+        if !matches!(&*start.word, ":" | ":!") {
+            i += 1;
+            continue;
+        }
+        let end = definition_end(&tokens, i)?;
+        let ops = t.compile(&tokens[i..=end]);
+        let def = ops
+            .filter(|_| start.word == ":")
+            .and_then(|ops| t.generate(&tokens[i + 1].word, &ops).ok());
+        if let Some(def) = def {
+            rest.push_str(core::str::from_utf8(&source[copied..start.span.start])?);
+            write!(rest, "{} !aot", defs.len())?;
+            copied = tokens[end].span.end;
+            defs.push(def);
+        }
+        i = end + 1;
+    }
+    rest.push_str(core::str::from_utf8(&source[copied..])?);
+    generate(name, &defs, &rest)
+}
+
+/// Find the `;` that ends the definition starting at `start`.
+fn definition_end(tokens: &[Token], start: usize) -> Result<usize> {
+    let mut i = start + 2;
+    while let Some(t) = tokens.get(i) {
+        match &*t.word {
+            ";" => return Ok(i),
+            "?" | "&" => i += 2,
+            _ => i += 1,
+        }
+    }
+    let t = &tokens[start];
+    let line = t.source[..t.span.start]
+        .iter()
+        .filter(|x| **x == b'\n')
+        .count()
+        + 1;
+    Err(format!("definition at line {line} is never finished").into())
+}
+
+/// A VM that compiles the definitions of a script without running it.
+struct Translator<F> {
+    root: Root<F>,
+    /// Operations of the last finished definition.
+    last: Rc<Cell<Option<Rc<[Op]>>>>,
+    /// Stand-ins for words that are only defined once the script runs, with their names.
+    placeholders: Vec<(Word, Box<str>)>,
+}
+
+impl Translator<()> {
+    fn new() -> Translator<impl Fn() -> Result<Option<String>>> {
+        let root = Root::new();
+        // the script may redefine words while it runs, so they are looked up by name
+        // where a translated definition is defined, like the interpreter would
+        root.comp.set_inline(false);
+        let last = Rc::<Cell<Option<Rc<[Op]>>>>::default();
+        let (c, d, l) = (root.comp.clone(), root.dictionary.clone(), last.clone());
+        root.dictionary.imm(";", move || {
+            let (name, immediate, ops) = c.finish_ops()?;
+            let ops = Rc::<[Op]>::from(ops);
+            l.set(Some(ops.clone()));
+            c.define_ops(&d, &name, immediate, ops)
+                .map_or(Ok(()), |x| (x)())
+        });
+        Translator {
+            root,
+            last,
+            placeholders: vec![],
+        }
+    }
+}
+
+impl<F> Translator<F>
+where
+    F: Fn() -> Result<Option<String>>,
+{
+    /// Compile a definition and get its operations,
+    /// or [`None`] if it can only be compiled while the script runs.
+    fn compile(&mut self, tokens: &[Token]) -> Option<Rc<[Op]>> {
+        let d = &self.root.dictionary;
+        let missing = tokens[2..]
+            .iter()
+            .filter(|t| d.get(&t.word).is_none())
+            .map(|t| t.word.clone())
+            .collect::<Vec<_>>();
+        missing.iter().for_each(|x| self.placeholder(x));
+        let (first, last) = (&tokens[0], &tokens[tokens.len() - 1]);
+        let root = &self.root;
+        root.push_source(first.source[first.span.start..last.span.end].into());
+        let res = root.interpret();
+        root.streams.with(|s| s.clear());
+        root.int.with(|v| v.clear());
+        root.obj.with(|v| v.clear());
+        root.float.with(|v| v.clear());
+        if res.is_err() {
+            root.comp.abandon();
+            // later definitions must not inline an older definition of the name
+            self.placeholder(&tokens[1].word);
+            return None;
+        }
+        self.last.take()
+    }
+
+    /// Define a word that is only defined once the script runs.
+    fn placeholder(&mut self, name: &str) {
+        let n = Box::<str>::from(name);
+        let w = self
+            .root
+            .comp
+            .with(move || Err(format!("{n} is only defined when the script runs").into()));
+        // a name lets it have an execution token, but it must not become a native word
+        w.set_sym(Sym::Native(name.into()));
+        let w2 = w.clone();
+        self.root
+            .dictionary
+            .0
+            .with(|d| d.words.insert(name.into(), w2));
+        self.placeholders.push((w, name.into()));
+    }
+
+    /// Find out how translated code refers to a word.
+    fn target(&self, word: &Word) -> Result<Target> {
+        let is = |w: &Word| {
+            w.ptr_eq(word) || matches!(w.0.ops.as_deref(), Some([Op::Call(x, _)]) if x.ptr_eq(word))
+        };
+        if let Some((_, name)) = self.placeholders.iter().find(|(w, _)| is(w)) {
+            return Ok(Target::Name(name.clone()));
+        }
+        match word.sym() {
+            Sym::Native(path) => Ok(Target::Native(path)),
+            Sym::Entry(n, _) => {
+                let e = &self.root.comp.entries()[n];
+                if !matches!(&*e.kind, ":" | ":!") {
+                    return Err(format!("{} {} can't be referenced", e.kind, e.name).into());
+                }
+                Ok(Target::Name(e.name.clone()))
+            }
+            Sym::None => Err("word has no stable name and can't be referenced".into()),
+        }
+    }
+
+    fn generate(&self, name: &str, ops: &[Op]) -> Result<Def> {
+        let mut g = Gen {
+            t: self,
+            def: Def {
+                name: name.into(),
+                code: String::new(),
+                refs: vec![],
+                lits: vec![],
+            },
+        };
+        g.ops(ops, 1)?;
+        Ok(g.def)
+    }
+}
+
+/// Writes operations as the body of a Rust function.
+struct Gen<'a, F> {
+    t: &'a Translator<F>,
+    def: Def,
+}
+
+impl<F> Gen<'_, F>
+where
+    F: Fn() -> Result<Option<String>>,
+{
+    fn line(&mut self, depth: usize, line: &str) {
+        let indent = "    ".repeat(depth);
+        writeln!(self.def.code, "{indent}{line}").unwrap();
+    }
+
+    fn reference(&mut self, target: Target) -> usize {
+        let refs = &mut self.def.refs;
+        refs.iter().position(|x| *x == target).unwrap_or_else(|| {
+            refs.push(target);
+            refs.len() - 1
+        })
+    }
+
+    fn lit(&mut self, depth: usize, lit: String) {
+        let lits = &mut self.def.lits;
+        let i = lits.iter().position(|x| *x == lit).unwrap_or_else(|| {
+            lits.push(lit);
+            lits.len() - 1
+        });
+        self.line(depth, &format!("x.lit({i})?;"));
+    }
+
+    fn ops(&mut self, ops: &[Op], depth: usize) -> Result<()> {
+        for op in ops {
+            match op {
+                Op::Int(x) => match i64::try_from(x.clone()) {
+                    Ok(x) => self.line(depth, &format!("x.push_int(Int::from({x}_i64))?;")),
+                    Err(_) => self.lit(depth, format!("Lit::Int({:?})", x.to_string())),
+                },
+                Op::Float(x) => {
+                    let x = x.to_bits();
+                    self.line(depth, &format!("x.push_float(f64::from_bits({x:#x}))?;"));
+                }
+                Op::Obj(x) => {
+                    let lit = match self.t.root.comp.token_word(x) {
+                        Ok(w) => format!("Lit::Token({})", self.reference(self.t.target(&w)?)),
+                        Err(_) => format!("Lit::Obj(&{:?})", encode::encode(x)),
+                    };
+                    self.lit(depth, lit);
+                }
+                Op::Call(w, _) => match self.t.target(w)? {
+                    Target::Native(path) if let Some(code) = inline(&path) => {
+                        self.line(depth, &format!("// {path}"));
+                        code.lines().for_each(|x| self.line(depth, x));
+                    }
+                    target => {
+                        let i = self.reference(target);
+                        self.line(depth, &format!("x.call({i})?;"));
+                    }
+                },
+                Op::Cond(c) => {
+                    let kw = if c.repeat { "while" } else { "if" };
+                    self.line(depth, &format!("{kw} {{"));
+                    self.ops(&c.cond, depth + 1)?;
+                    self.line(depth + 1, "x.test()?");
+                    self.line(depth, "} {");
+                    self.ops(&c.tru, depth + 1)?;
+                    if c.repeat {
+                        self.line(depth, "}");
+                        self.ops(&c.fals, depth)?;
+                    } else {
+                        self.line(depth, "} else {");
+                        self.ops(&c.fals, depth + 1)?;
+                        self.line(depth, "}");
+                    }
+                }
+                Op::Transaction(ops) => {
+                    self.line(depth, "x.transaction(|| {");
+                    self.ops(ops, depth + 1)?;
+                    self.line(depth + 1, "Ok(())");
+                    self.line(depth, "})?;");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Get the Rust for a native word that is written out instead of called.
+///
+/// It fails the same way as the word.
+fn inline(path: &str) -> Option<String> {
+    let int2 = |x: &str| format!("let (b, a) = (x.pop_int()?, x.pop_int()?);\nx.push_int({x})?;");
+    let checked = |x: &str| {
+        int2(&format!(
+            "{x}.ok_or({:?})?",
+            format!("{path}: invalid operands")
+        ))
+    };
+    let obj1 = |x: &str| format!("let a = x.pop_obj()?;\n{x}");
+    let obj2 = |x: &str| format!("let (b, a) = (x.pop_obj()?, x.pop_obj()?);\n{x}");
+    let (prefix, name) = path.split_at_checked(1)?;
+    let stack = match prefix {
+        "#" => "int",
+        "@" => "obj",
+        _ => "",
+    };
+    let shuffle = |n: usize, f: &str| format!("x.shuffle_{stack}({path:?}, {n}, |v, l| {f})?;");
+    Some(match (path, stack, name) {
+        ("+", ..) => int2("a + b"),
+        ("-", ..) => int2("a - b"),
+        ("*", ..) => int2("a * b"),
+        ("=", ..) => int2("Int::from(a == b)"),
+        ("<>", ..) => int2("Int::from(a != b)"),
+        ("<", ..) => int2("Int::from(a < b)"),
+        (">", ..) => int2("Int::from(a > b)"),
+        ("<=", ..) => int2("Int::from(a <= b)"),
+        (">=", ..) => int2("Int::from(a >= b)"),
+        ("#min", ..) => int2("a.min(b)"),
+        ("#max", ..) => int2("a.max(b)"),
+        ("#bit:shl", ..) => checked("usize::try_from(b).ok().map(|b| a << b)"),
+        ("#bit:shr", ..) => checked("usize::try_from(b).ok().map(|b| a >> b)"),
+        ("#bit:and", ..) => int2("a & b"),
+        ("#bit:or", ..) => int2("a | b"),
+        ("#bit:xor", ..) => int2("a ^ b"),
+        ("@concat", ..) => obj2("x.push_obj(a.concat(&b))?;"),
+        ("@=", ..) => obj2("x.push_int(Int::from(a == b))?;"),
+        ("@intoref", ..) => obj1("x.push_obj(Object::from([a]))?;"),
+        ("@reverse", ..) => obj1("x.push_obj(a.reverse())?;"),
+        ("@refcount", ..) => obj1("x.push_int(Int::from(a.refs().len()))?;"),
+        ("@bytecount", ..) => obj1("x.push_int(Int::from(a.data().len()))?;"),
+        (_, "", _) => return None,
+        (_, s, "dup") => format!("let a = x.pop_{s}()?;\nx.push_{s}(a.clone())?;\nx.push_{s}(a)?;"),
+        (_, s, "drop") => format!("x.pop_{s}()?;"),
+        (_, s, "swap") => {
+            format!("let (b, a) = (x.pop_{s}()?, x.pop_{s}()?);\nx.push_{s}(b)?;\nx.push_{s}(a)?;")
+        }
+        (.., "over") => shuffle(2, "v.push(v[l - 2].clone())"),
+        (.., "rot") => shuffle(3, "v[l - 3..].rotate_left(1)"),
+        (.., "-rot") => shuffle(3, "v[l - 3..].rotate_right(1)"),
+        (.., "nip") => shuffle(2, "drop(v.remove(l - 2))"),
+        (.., "tuck") => shuffle(2, "v.insert(l - 2, v[l - 1].clone())"),
+        (.., "2drop") => shuffle(2, "v.truncate(l - 2)"),
+        (.., "2swap") => shuffle(4, "v[l - 4..].rotate_left(2)"),
+        _ => return None,
+    })
+}
+
+fn generate(name: &str, defs: &[Def], rest: &str) -> Result<String> {
+    let mut s = String::new();
+    writeln!(s, "//! Generated by `damned build` from `{name}`.")?;
+    writeln!(s, "//!")?;
+    writeln!(s, "//! Build it in a crate that depends on `damned`.")?;
+    writeln!(s)?;
+    let code = defs.iter().map(|x| &*x.code).collect::<String>();
+    let mut uses = vec!["self", "Ctx"];
+    uses.extend(code.contains("Int::").then_some("Int"));
+    uses.extend(defs.iter().any(|x| !x.lits.is_empty()).then_some("Lit"));
+    uses.push("Native");
+    uses.extend(code.contains("Object::").then_some("Object"));
+    uses.extend(defs.iter().any(|x| !x.refs.is_empty()).then_some("Ref"));
+    uses.push("Result");
+    writeln!(s, "use damned::script::aot::{{{}}};", uses.join(", "))?;
+    for (i, def) in defs.iter().enumerate() {
+        writeln!(s)?;
+        writeln!(s, "/// `{}`", def.name.replace('`', "'"))?;
+        let x = if def.code.is_empty() { "_" } else { "x" };
+        writeln!(s, "fn w{i}({x}: &Ctx) -> Result<()> {{")?;
+        s.push_str(&def.code);
+        writeln!(s, "    Ok(())")?;
+        writeln!(s, "}}")?;
+    }
+    writeln!(s)?;
+    writeln!(s, "const WORDS: &[Native] = &[")?;
+    for (i, def) in defs.iter().enumerate() {
+        writeln!(s, "    Native {{")?;
+        writeln!(s, "        name: {:?},", def.name)?;
+        writeln!(s, "        f: w{i},")?;
+        if def.refs.is_empty() {
+            writeln!(s, "        refs: &[],")?;
+        } else {
+            writeln!(s, "        refs: &[")?;
+            for r in &def.refs {
+                match r {
+                    Target::Native(x) => writeln!(s, "            Ref::Native({x:?}),")?,
+                    Target::Name(x) => writeln!(s, "            Ref::Name({x:?}),")?,
+                }
+            }
+            writeln!(s, "        ],")?;
+        }
+        if def.lits.is_empty() {
+            writeln!(s, "        lits: &[],")?;
+        } else {
+            writeln!(s, "        lits: &[")?;
+            for x in &def.lits {
+                writeln!(s, "            {x},")?;
+            }
+            writeln!(s, "        ],")?;
+        }
+        writeln!(s, "    }},")?;
+    }
+    writeln!(s, "];")?;
+    writeln!(s)?;
+    writeln!(s, "const SOURCE: &str = {rest:?};")?;
+    writeln!(s)?;
+    writeln!(s, "fn main() {{")?;
+    writeln!(
+        s,
+        "    damned::run_tui(aot::run(WORDS, SOURCE, std::env::args().skip(1)));"
+    )?;
+    writeln!(s, "}}")?;
+    Ok(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::translate;
+
+    /// Get the generated function of the `i`th translated definition.
+    fn function(out: &str, i: usize) -> &str {
+        let start = out.find(&format!("fn w{i}(")).unwrap();
+        &out[start..start + out[start..].find("\n}\n").unwrap()]
+    }
+
+    #[test]
+    fn definitions() {
+        let out = translate(
+            br##"
+            :! twice "#dup" !call "+" !call ;
+            : f if #dup 0 < then if #dup -1 = then 1 + else end else twice end ;
+            : g & f "x" 99999999999999999999 1 2 + ;
+            : h "f" !call transaction 1 #>r #r> end ;
+            Var integer v
+            : k v 1 #bit:shl ;
+            "##,
+            "t.damned",
+        )
+        .unwrap();
+        assert!(out.contains(r#"const SOURCE: &str = "\n            :! twice"#));
+        for i in 0..4 {
+            assert!(out.contains(&format!("{i} !aot")));
+        }
+        let f = function(&out, 0);
+        assert!(!f.contains("x.call"));
+        assert_eq!(f.matches("if {").count(), 2);
+        assert!(f.contains("x.push_int(a + b)?;"));
+        let g = function(&out, 1);
+        assert!(g.contains("x.push_int(Int::from(3_i64))?;"));
+        assert!(out.contains(r#"Ref::Name("f"),"#));
+        assert!(out.contains("Lit::Token(0),"));
+        assert!(out.contains(r#"Lit::Int("99999999999999999999"),"#));
+        let h = function(&out, 2);
+        assert!(h.contains("x.transaction(|| {"));
+        assert!(out.contains(r#"Ref::Native("!call"),"#));
+        assert!(out.contains(r#"Ref::Name("v"),"#));
+        assert!(function(&out, 3).contains(r##".ok_or("#bit:shl: invalid operands")?"##));
+    }
+
+    #[test]
+    fn fallback() {
+        let out = translate(
+            b": a 1 ;
+            Var integer v
+            :! m v !integer ;
+            : b m ;
+            : c b a ;",
+            "t.damned",
+        )
+        .unwrap();
+        // `m` can only run once `v` exists, so `b` is compiled when the script runs
+        assert!(out.contains(r#"const SOURCE: &str = "0 !aot\n"#));
+        assert!(out.contains(r#": b m ;\n            1 !aot""#));
+        assert!(out.contains(r#"Ref::Name("b"),"#));
+        assert!(out.contains(r#"Ref::Name("a"),"#));
+        let e = translate(b": a 1", "t.damned").err().unwrap();
+        assert_eq!(e.to_string(), "definition at line 1 is never finished");
+    }
+}
@@ -0,0 +1,186 @@
+use super::{
+    Compiler, Dictionary, Int, Object, Stack, Word,
+    image::{Persist, Reader},
+    with_imm,
+};
+use std::rc::Rc;
+use with_cell::WithCell;
+
+/// Longest array, so that a wrong length fails instead of exhausting memory.
+const MAX_LEN: usize = 1 << 24;
+
+fn length(n: Int) -> super::Result<usize> {
+    usize::try_from(n.clone())
+        .ok()
+        .filter(|n| *n <= MAX_LEN)
+        .ok_or_else(|| format!("array length {n} is not between 0 and {MAX_LEN}").into())
+}
+
+pub fn define<F>(
+    comp: &Compiler,
+    read_word: &Rc<F>,
+    d: &Dictionary,
+    int: &Rc<Stack<Int>>,
+    obj: &Rc<Stack<Object>>,
+) where
+    F: 'static + Fn() -> super::Result<Option<String>>,
+{
+    fn f<T, F>(
+        comp: &Compiler,
+        d: &Dictionary,
+        read_word: &Rc<F>,
+        (stack, int): (&Rc<Stack<T>>, &Rc<Stack<Int>>),
+        kind: &'static str,
+    ) -> Word
+    where
+        F: 'static + Fn() -> super::Result<Option<String>>,
+        T: 'static + Default + Clone + Persist,
+    {
+        let (c, d2, s, i) = (comp.clone(), d.clone(), stack.clone(), int.clone());
+        comp.loader(kind, move |name, r: &mut Reader| {
+            let v = (0..r.usize()?)
+                .map(|_| T::load(r))
+                .collect::<super::Result<_>>()?;
+            create(&c, &d2, (&s, &i), kind, name).with(|x| *x = v);
+            Ok(())
+        });
+        let comp = comp.clone();
+        let read_word = read_word.clone();
+        let (s, i) = (stack.clone(), int.clone());
+        let d = d.clone();
+        with_imm(move || {
+            let name = read_word()?.unwrap();
+            let n = length(i.pop()?)?;
+            create(&comp, &d, (&s, &i), kind, &name).with(|x| x.resize(n, T::default()));
+            Ok(())
+        })
+    }
+    let int_a = (
+        "integer",
+        f(comp, d, read_word, (int, int), "Array integer"),
+    );
+    let obj_a = ("object", f(comp, d, read_word, (obj, int), "Array object"));
+    d.dict("Array", read_word, &[int_a, obj_a]);
+}
+
+/// Define the words of an array.
+fn create<T>(
+    comp: &Compiler,
+    d: &Dictionary,
+    (s, int): (&Rc<Stack<T>>, &Rc<Stack<Int>>),
+    kind: &str,
+    name: &str,
+) -> Rc<WithCell<Vec<T>>>
+where
+    T: 'static + Default + Clone + Persist,
+{
+    let x = Rc::<WithCell<Vec<T>>>::default();
+    let index = |x: &[T], i: Int| {
+        usize::try_from(i.clone())
+            .ok()
+            .filter(|i| *i < x.len())
+            .ok_or_else(|| format!("index {i} is out of bounds for length {}", x.len()))
+    };
+    let (x2, s2, i2) = (x.clone(), s.clone(), int.clone());
+    let get = comp.with(move || {
+        let i = i2.pop()?;
+        let v = x2.with(|x| index(x, i).map(|i| x[i].clone()))?;
+        s2.push(v)
+    });
+    let touch = {
+        let (x, c) = (x.clone(), comp.clone());
+        move || {
+            c.touch(Rc::as_ptr(&x).cast(), || {
+                let (x, v) = (x.clone(), x.with(|v| v.clone()));
+                move || x.with(|x| *x = v)
+            })
+        }
+    };
+    let (x2, s2, i2, t) = (x.clone(), s.clone(), int.clone(), touch.clone());
+    let set = comp.with(move || {
+        let i = i2.pop()?;
+        let v = s2.pop()?;
+        t();
+        x2.with(|x| index(x, i).map(|i| x[i] = v))?;
+        Ok(())
+    });
+    let (x2, i2) = (x.clone(), int.clone());
+    let len = comp.with(move || i2.push(x2.with(|x| x.len()).into()));
+    let (x2, i2) = (x.clone(), int.clone());
+    let resize = comp.with(move || {
+        let n = length(i2.pop()?)?;
+        touch();
+        x2.with(|x| x.resize(n, T::default()));
+        Ok(())
+    });
+    let x2 = x.clone();
+    let words = [get.clone(), set.clone(), len.clone(), resize.clone()];
+    comp.log(kind, name, &words, move |w| {
+        x2.with(|x| {
+            w.usize(x.len());
+            x.iter().for_each(|v| v.save(w));
+        });
+        Ok(())
+    });
+    d.define(name, get);
+    d.define(&format!("set:{name}"), set);
+    d.define(&format!("len:{name}"), len);
+    d.define(&format!("resize:{name}"), resize);
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Root, eval};
+    use super::*;
+
+    fn error(source: &str) -> String {
+        eval(source).err().unwrap().to_string()
+    }
+
+    #[test]
+    fn bounds() {
+        let e = error("3 Array integer a 3 a");
+        assert_eq!(e, "index 3 is out of bounds for length 3");
+        let e = error("3 Array integer a 1 -1 set:a");
+        assert_eq!(e, "index -1 is out of bounds for length 3");
+        let e = error("0 Array object a \"x\" 0 set:a");
+        assert_eq!(e, "index 0 is out of bounds for length 0");
+        let e = error("16777217 Array integer a");
+        assert_eq!(e, "array length 16777217 is not between 0 and 16777216");
+        let e = error("1 Array integer a -1 resize:a");
+        assert_eq!(e, "array length -1 is not between 0 and 16777216");
+        let e = error("1 Array integer a 16777217 resize:a");
+        assert_eq!(e, "array length 16777217 is not between 0 and 16777216");
+    }
+
+    #[test]
+    fn resize() {
+        let root = eval(
+            "3 Array integer a 7 2 set:a 5 resize:a len:a 2 a 4 a
+            1 resize:a len:a 0 a 3 resize:a 2 a",
+        )
+        .unwrap();
+        let v = [5, 7, 0, 1, 0, 0].map(Int::from);
+        assert_eq!(root.int.with(|v| v.clone()), v);
+    }
+
+    #[test]
+    fn rollback() {
+        let root = Root::new();
+        root.push_source(
+            b"2 Array object a \"x\" 0 set:a
+            transaction \"y\" 0 set:a \"z\" 1 set:a 5 resize:a @drop end"
+                .into(),
+        );
+        let e = root.interpret().unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "stack damned::script::object::Object is empty"
+        );
+        root.push_source(b"len:a 0 a 1 a".into());
+        root.interpret().unwrap();
+        assert_eq!(root.int.with(|v| v.clone()), [2.into()]);
+        assert_eq!(root.obj.with(|v| v.clone()), ["x".into(), "".into()]);
+    }
+}
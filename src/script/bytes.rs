@@ -0,0 +1,127 @@
+use super::{Compiler, Dictionary, Int, Object, Stack};
+use num::{BigInt, bigint::Sign};
+use std::rc::Rc;
+
+/// Encode an integer, in `width` bytes or as few as possible if `width` is 0.
+fn encode(x: Int, width: usize, signed: bool, big: bool) -> super::Result<Vec<u8>> {
+    let x = x.to_bigint();
+    let negative = x.sign() == Sign::Minus;
+    if negative && !signed {
+        return Err(format!("{x} is negative but must be unsigned").into());
+    }
+    let mut v = if signed {
+        x.to_signed_bytes_le()
+    } else {
+        x.to_bytes_le().1
+    };
+    if width != 0 {
+        if v.len() > width {
+            return Err(format!("{x} doesn't fit in {width} bytes").into());
+        }
+        v.resize(width, if negative { 0xff } else { 0 });
+    }
+    if big {
+        v.reverse();
+    }
+    Ok(v)
+}
+
+fn decode(x: &[u8], signed: bool, big: bool) -> Int {
+    match (signed, big) {
+        (true, true) => BigInt::from_signed_bytes_be(x),
+        (true, false) => BigInt::from_signed_bytes_le(x),
+        (false, true) => BigInt::from_bytes_be(Sign::Plus, x),
+        (false, false) => BigInt::from_bytes_le(Sign::Plus, x),
+    }
+    .into()
+}
+
+pub fn define<F>(
+    comp: &Compiler,
+    dictionary: &Dictionary,
+    read_word: &Rc<F>,
+    int: &Rc<Stack<Int>>,
+    obj: &Rc<Stack<Object>>,
+) where
+    F: 'static + Fn() -> super::Result<Option<String>>,
+{
+    let from = |signed, big| {
+        let (int, obj) = (int.clone(), obj.clone());
+        comp.with(move || {
+            let width = usize::try_from(int.pop()?)?;
+            obj.push(encode(int.pop()?, width, signed, big)?.into())
+        })
+    };
+    let to = |signed, big| {
+        let (int, obj) = (int.clone(), obj.clone());
+        comp.with(move || int.push(decode(obj.pop()?.data(), signed, big)))
+    };
+    dictionary.dict(
+        "Bytes",
+        read_word,
+        &[
+            ("from-int-le", from(true, false)),
+            ("from-int-be", from(true, true)),
+            ("from-uint-le", from(false, false)),
+            ("from-uint-be", from(false, true)),
+            ("to-int-le", to(true, false)),
+            ("to-int-be", to(true, true)),
+            ("to-uint-le", to(false, false)),
+            ("to-uint-be", to(false, true)),
+        ],
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let max = |bits: u32| (BigInt::from(1) << bits) - 1;
+        for width in [1, 2, 8] {
+            let bits = width as u32 * 8;
+            let signed = [
+                -(BigInt::from(1) << (bits - 1)),
+                BigInt::from(-1),
+                BigInt::from(0),
+                BigInt::from(1),
+                max(bits - 1),
+            ];
+            let unsigned = [BigInt::from(0), BigInt::from(1), max(bits)];
+            let cases = signed
+                .iter()
+                .map(|x| (x, true))
+                .chain(unsigned.iter().map(|x| (x, false)));
+            for (x, is_signed) in cases {
+                for big in [false, true] {
+                    let v = encode(x.clone().into(), width, is_signed, big).unwrap();
+                    assert_eq!(v.len(), width);
+                    assert_eq!(decode(&v, is_signed, big).to_bigint(), *x);
+                }
+            }
+        }
+        assert_eq!(encode(0x102.into(), 2, false, false).unwrap(), [2, 1]);
+        assert_eq!(encode(0x102.into(), 2, false, true).unwrap(), [1, 2]);
+        assert_eq!(encode((-2).into(), 0, true, false).unwrap(), [0xfe]);
+    }
+
+    #[test]
+    fn out_of_range() {
+        let one = || BigInt::from(1);
+        for width in [1, 2, 8] {
+            let bits = width as u32 * 8;
+            let cases = [
+                (-(one() << (bits - 1)) - 1, true),
+                (one() << (bits - 1), true),
+                (one() << bits, false),
+                (BigInt::from(-1), false),
+            ];
+            for (x, signed) in cases {
+                for big in [false, true] {
+                    assert!(encode(x.clone().into(), width, signed, big).is_err());
+                }
+            }
+        }
+    }
+}
@@ -1,26 +1,119 @@
-use super::{BigInt, Dictionary, Object, Stack, Word, with_imm};
-use std::rc::Rc;
+use super::{
+    Dictionary, Int, Object, Stack, Word,
+    image::{Entry, Loader, Reader, Sym, Writer, read_usize, write_usize},
+    with_imm,
+};
+use core::cell::Cell;
+use std::{collections::BTreeMap, rc::Rc};
 use with_cell::WithCell;
 
+/// Maximum amount of operations a word may have to be inlined.
+const INLINE_LIMIT: usize = 4;
+/// First byte of an execution token, which is never valid UTF-8 so no string is a token.
+const TOKEN: u8 = 0xfe;
+
 #[derive(Clone)]
-pub struct Compiler(Rc<WithCell<Option<CompilerData>>>);
+pub struct Compiler(Rc<CompilerState>);
+
+struct CompilerState {
+    data: WithCell<Option<CompilerData>>,
+    int: Rc<Stack<Int>>,
+    obj: Rc<Stack<Object>>,
+    float: Rc<Stack<f64>>,
+    optimize: Cell<bool>,
+    /// Whether short definitions may be inlined.
+    inline: Cell<bool>,
+    /// Auxiliary stacks of `#>r` and `@>r`.
+    aux_int: Rc<Stack<Int>>,
+    aux_obj: Rc<Stack<Object>>,
+    /// Depths of the auxiliary stacks when the running word was called.
+    aux_base: Cell<[usize; 2]>,
+    /// Everything that has been defined, in order.
+    log: WithCell<Vec<Rc<Entry>>>,
+    /// Functions to redefine logged entries, by kind.
+    loaders: WithCell<BTreeMap<Box<str>, Loader>>,
+    /// How to undo changes to cells, per running transaction.
+    journal: WithCell<Vec<Journal>>,
+    /// Words referred to by execution tokens, by index.
+    tokens: WithCell<Vec<Word>>,
+}
+
+/// Functions to restore cells, by the address of the cell.
+type Journal = BTreeMap<usize, Box<dyn FnOnce()>>;
 
 struct CompilerData {
     name: Box<str>,
-    words: Vec<Word>,
-    cond: Option<Cond>,
+    words: Vec<Op>,
+    /// Unfinished conditions and transactions, innermost last.
+    frames: Vec<Frame>,
     immediate: bool,
 }
 
+/// A single operation in a compiled word.
+#[derive(Clone)]
+pub enum Op {
+    /// Push an integer literal.
+    Int(Int),
+    /// Push an object literal.
+    Obj(Object),
+    /// Push a float literal.
+    Float(f64),
+    /// Call a word.
+    Call(Word, Hint),
+    /// Run a condition or loop.
+    Cond(Rc<CondOps>),
+    /// Run operations, restoring the stacks and touched cells if they fail.
+    Transaction(Rc<[Op]>),
+}
+
+/// The parts of an `if ... end` or `if ... repeat`.
+pub struct CondOps {
+    pub repeat: bool,
+    pub cond: Box<[Op]>,
+    pub tru: Box<[Op]>,
+    pub fals: Box<[Op]>,
+}
+
+/// What the optimizer may assume about a word.
+#[derive(Clone, Copy)]
+pub enum Hint {
+    /// Nothing is known about the word.
+    Opaque,
+    /// Pops two integers and pushes one without any other side effects.
+    ///
+    /// [`None`] means the operation fails and must not be folded.
+    Int2(fn(Int, Int) -> Option<Int>),
+    IntDup,
+    IntDrop,
+    IntSwap,
+    ObjDup,
+    ObjDrop,
+    ObjSwap,
+    /// Moves an integer to the auxiliary stack.
+    IntToAux,
+    /// Moves an integer from the auxiliary stack.
+    IntFromAux,
+    /// Copies an integer from the auxiliary stack.
+    IntCopyAux,
+    ObjToAux,
+    ObjFromAux,
+    ObjCopyAux,
+}
+
+/// An unfinished construct in a definition.
+enum Frame {
+    Cond(Cond),
+    Transaction(Vec<Op>),
+}
+
 #[derive(Default)]
 struct Cond {
-    cond: Vec<Word>,
-    tru: Vec<Word>,
-    fals: Vec<Word>,
+    cond: Vec<Op>,
+    tru: Vec<Op>,
+    fals: Vec<Op>,
     stage: CondStage,
 }
-
-#[derive(Default)]
+#[derive(Default, PartialEq)]
 enum CondStage {
     #[default]
     Cond,
@@ -33,36 +126,149 @@ impl CompilerData {
         Self {
             name: name.into(),
             words: Default::default(),
-            cond: None,
+            frames: Default::default(),
             immediate,
         }
     }
 
-    pub fn push(&mut self, word: Word) {
-        let v = if let Some(c) = self.cond.as_mut() {
-            match &c.stage {
+    pub fn push(&mut self, op: Op) {
+        let v = match self.frames.last_mut() {
+            Some(Frame::Cond(c)) => match &c.stage {
                 CondStage::Cond => &mut c.cond,
                 CondStage::True => &mut c.tru,
                 CondStage::False => &mut c.fals,
-            }
-        } else {
-            &mut self.words
+            },
+            Some(Frame::Transaction(v)) => v,
+            None => &mut self.words,
         };
-        v.push(word);
+        v.push(op);
+    }
+}
+
+impl Op {
+    fn hint(&self) -> Option<Hint> {
+        match self {
+            Self::Int(_) | Self::Obj(_) | Self::Float(_) => None,
+            Self::Call(_, h) => Some(*h),
+            Self::Cond(_) | Self::Transaction(_) => Some(Hint::Opaque),
+        }
     }
 }
 
-impl Cond {
-    fn finish(self) -> super::Result<[Box<[Word]>; 3]> {
-        let Self {
-            cond,
-            tru,
-            fals,
-            stage,
-        } = self;
-        assert!(matches!(stage, CondStage::True | CondStage::False));
-        Ok([cond, tru, fals].map(|x| x.into_boxed_slice()))
+/// Fold constants and remove redundant stack operations.
+fn optimize(ops: Vec<Op>) -> Vec<Op> {
+    let mut out = Vec::with_capacity(ops.len());
+    for op in ops {
+        out.push(op);
+        while reduce(&mut out) {}
     }
+    out
+}
+
+/// Simplify the tail of a sequence of operations.
+///
+/// Returns `true` if anything changed.
+fn reduce(ops: &mut Vec<Op>) -> bool {
+    use Hint::*;
+    let [.., x, y] = &ops[..] else {
+        return false;
+    };
+    // pairs like `#dup #drop` are only removed once their operands are literals,
+    // as they fail on a short stack
+    match (x, y.hint()) {
+        (Op::Int(_), Some(IntDrop)) | (Op::Obj(_), Some(ObjDrop)) => {
+            ops.truncate(ops.len() - 2);
+            return true;
+        }
+        (Op::Int(_), Some(IntDup)) | (Op::Obj(_), Some(ObjDup)) => {
+            let x = x.clone();
+            *ops.last_mut().unwrap() = x;
+            return true;
+        }
+        _ => {}
+    }
+    let [.., Op::Int(x), Op::Int(y), z] = &ops[..] else {
+        return false;
+    };
+    match z.hint() {
+        Some(Int2(f)) => {
+            let Some(z) = (f)(x.clone(), y.clone()) else {
+                return false;
+            };
+            ops.truncate(ops.len() - 3);
+            ops.push(Op::Int(z));
+            true
+        }
+        Some(IntSwap) => {
+            let (x, y) = (x.clone(), y.clone());
+            ops.truncate(ops.len() - 3);
+            ops.extend([Op::Int(y), Op::Int(x)]);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Get the operation that calls a word, keeping the hint of native words.
+fn call_op(word: Word) -> Op {
+    match word.0.ops.as_deref() {
+        Some([op @ Op::Call(..)]) => op.clone(),
+        _ => Op::Call(word, Hint::Opaque),
+    }
+}
+
+/// Check whether operations use the auxiliary stacks.
+fn uses_aux(ops: &[Op]) -> bool {
+    use Hint::*;
+    ops.iter().any(|op| match op {
+        Op::Cond(c) => [&c.cond, &c.tru, &c.fals].iter().any(|x| uses_aux(x)),
+        Op::Transaction(x) => uses_aux(x),
+        _ => matches!(
+            op.hint(),
+            Some(IntToAux | IntFromAux | IntCopyAux | ObjToAux | ObjFromAux | ObjCopyAux)
+        ),
+    })
+}
+
+/// Track the depth of the auxiliary stacks through a sequence of operations.
+///
+/// Fails if a value would be taken that the operations didn't put there
+/// or if a transaction or any part of a condition changes the depth.
+fn aux_depth(ops: &[Op], mut depth: [usize; 2]) -> Result<[usize; 2], &'static str> {
+    use Hint::*;
+    for op in ops {
+        let (i, take) = match op {
+            Op::Cond(c) => {
+                for x in [&c.cond, &c.tru, &c.fals] {
+                    if aux_depth(x, depth)? != depth {
+                        return Err("conditional changes the auxiliary stack");
+                    }
+                }
+                continue;
+            }
+            Op::Transaction(x) => {
+                if aux_depth(x, depth)? != depth {
+                    return Err("transaction changes the auxiliary stack");
+                }
+                continue;
+            }
+            _ => match op.hint() {
+                Some(IntToAux) => (0, None),
+                Some(IntFromAux) => (0, Some(true)),
+                Some(IntCopyAux) => (0, Some(false)),
+                Some(ObjToAux) => (1, None),
+                Some(ObjFromAux) => (1, Some(true)),
+                Some(ObjCopyAux) => (1, Some(false)),
+                _ => continue,
+            },
+        };
+        match take {
+            None => depth[i] += 1,
+            Some(_) if depth[i] == 0 => return Err("auxiliary stack is empty"),
+            Some(pop) => depth[i] -= usize::from(pop),
+        }
+    }
+    Ok(depth)
 }
 
 impl Compiler {
@@ -71,174 +277,534 @@ impl Compiler {
     where
         F: 'static + Fn() -> super::Result<()>,
     {
-        fn dyn_with(compiler: Compiler, f: Word) -> Word {
-            with_imm(move || {
-                // so much for WithCell...
-                if let Some(mut c) = compiler.0.take() {
-                    c.push(f.clone());
-                    compiler.0.set(Some(c));
-                    Ok(())
+        self.with_hint(Hint::Opaque, f)
+    }
+
+    /// Create a word from a closure with a hint for the optimizer.
+    pub fn with_hint<F>(&self, hint: Hint, f: F) -> Word
+    where
+        F: 'static + Fn() -> super::Result<()>,
+    {
+        self.with_ops(Rc::new([Op::Call(with_imm(f), hint)]))
+    }
+
+    /// Create a word that pushes an integer.
+    pub fn int(&self, x: Int) -> Word {
+        self.with_ops(Rc::new([Op::Int(x)]))
+    }
+
+    /// Create a word that pushes an object.
+    pub fn obj(&self, x: Object) -> Word {
+        self.with_ops(Rc::new([Op::Obj(x)]))
+    }
+
+    /// Create a word that pushes a float.
+    pub fn float(&self, x: f64) -> Word {
+        self.with_ops(Rc::new([Op::Float(x)]))
+    }
+
+    /// Create a word that is inlined when compiled.
+    fn with_ops(&self, ops: Rc<[Op]>) -> Word {
+        let c = self.clone();
+        let f = self.compile(&ops);
+        let o = ops.clone();
+        let f = move || {
+            // so much for WithCell...
+            if let Some(mut d) = c.0.data.take() {
+                o.iter().for_each(|op| d.push(op.clone()));
+                c.0.data.set(Some(d));
+                Ok(())
+            } else {
+                f.iter().try_for_each(|x| (x)())
+            }
+        };
+        Word::new(f, Some(ops), None)
+    }
+
+    /// Get the words a word runs, without compiling it if a definition is in progress.
+    pub fn body(&self, word: &Word) -> Box<[Word]> {
+        match &word.0.ops {
+            Some(ops) => self.compile(ops),
+            None => [word.clone()].into(),
+        }
+    }
+
+    /// Turn operations into words that can be executed.
+    fn compile(&self, ops: &[Op]) -> Box<[Word]> {
+        ops.iter().map(|op| self.compile_op(op)).collect()
+    }
+
+    fn compile_op(&self, op: &Op) -> Word {
+        match op {
+            Op::Int(x) => {
+                let (s, x) = (self.0.int.clone(), x.clone());
+                with_imm(move || s.push(x.clone()))
+            }
+            Op::Obj(x) => {
+                let (s, x) = (self.0.obj.clone(), x.clone());
+                with_imm(move || s.push(x.clone()))
+            }
+            Op::Float(x) => {
+                let (s, x) = (self.0.float.clone(), *x);
+                with_imm(move || s.push(x))
+            }
+            Op::Call(w, _) => w.clone(),
+            Op::Cond(c) => {
+                let [cond, tru, fals] = [&c.cond, &c.tru, &c.fals].map(|x| self.compile(x));
+                let stack = self.0.int.clone();
+                let test = move || {
+                    cond.iter().try_for_each(|x| (x)())?;
+                    Ok::<_, super::Error>(stack.pop()? != Int::ZERO)
+                };
+                if c.repeat {
+                    with_imm(move || {
+                        while (test)()? {
+                            tru.iter().try_for_each(|x| (x)())?;
+                        }
+                        fals.iter().try_for_each(|x| (x)())
+                    })
                 } else {
-                    (f)()
+                    with_imm(move || {
+                        let x = if (test)()? { &tru } else { &fals };
+                        x.iter().try_for_each(|x| (x)())
+                    })
                 }
-            })
+            }
+            Op::Transaction(ops) => {
+                let body = self.compile(ops);
+                let c = self.clone();
+                with_imm(move || c.transaction(|| body.iter().try_for_each(|x| (x)())))
+            }
         }
-        dyn_with(self.clone(), with_imm(f))
     }
 
-    fn finish(&self, dict: &Dictionary) -> Option<Word> {
-        let c = self.0.with(|x| x.take()).unwrap();
-        let x: Box<[_]> = c.words.into();
-        let f = move || x.iter().try_for_each(|x| (x)());
-        let x = if !c.immediate {
-            self.with(f)
+    /// Run a function, restoring the stacks and touched cells if it fails.
+    pub fn transaction<F>(&self, f: F) -> super::Result<()>
+    where
+        F: FnOnce() -> super::Result<()>,
+    {
+        let (int, obj, float) = (&self.0.int, &self.0.obj, &self.0.float);
+        let (ints, objs) = (int.with(|v| v.clone()), obj.with(|v| v.clone()));
+        let floats = float.with(|v| v.clone());
+        let aux = self.aux_len();
+        self.0.journal.with(|j| j.push(Journal::new()));
+        let res = f();
+        let undo = self.0.journal.with(|j| j.pop().unwrap());
+        if let Err(e) = res {
+            undo.into_values().for_each(|f| f());
+            int.with(|v| *v = ints);
+            obj.with(|v| *v = objs);
+            float.with(|v| *v = floats);
+            self.aux_truncate(aux);
+            Err(e)
         } else {
-            Rc::new(f)
-        };
-        if c.name.is_empty() {
-            Some(x)
+            // the enclosing transaction must be able to undo these changes too
+            self.0.journal.with(|j| {
+                if let Some(j) = j.last_mut() {
+                    undo.into_iter().for_each(|(k, f)| {
+                        j.entry(k).or_insert(f);
+                    });
+                }
+            });
+            Ok(())
+        }
+    }
+
+    fn optimize(&self, ops: Vec<Op>) -> Vec<Op> {
+        if self.0.optimize.get() {
+            optimize(ops)
         } else {
-            dict.define(&c.name, x);
-            None
+            ops
         }
     }
 
-    fn cond_begin(&self) -> super::Result<()> {
-        self.0.with(|c| {
-            let c = c.get_or_insert_with(|| CompilerData::new("", false));
-            assert!(c.cond.is_none(), "can't nest conditions");
-            c.cond = Some(Cond::default());
-            Ok(())
+    /// Enable or disable the optimizer for words that are compiled afterwards.
+    pub fn set_optimize(&self, enable: bool) {
+        self.0.optimize.set(enable)
+    }
+
+    /// Enable or disable inlining of words that are defined afterwards.
+    pub fn set_inline(&self, enable: bool) {
+        self.0.inline.set(enable)
+    }
+
+    fn finish(&self, dict: &Dictionary) -> super::Result<Option<Word>> {
+        let (name, immediate, ops) = self.finish_ops()?;
+        Ok(self.define_ops(dict, &name, immediate, ops.into()))
+    }
+
+    /// Finish the definition in progress and get its name, whether it is immediate
+    /// and its checked and optimized operations.
+    pub fn finish_ops(&self) -> super::Result<(Box<str>, bool, Vec<Op>)> {
+        let c = self.0.data.with(|x| x.take()).unwrap();
+        if !c.frames.is_empty() {
+            return Err(format!("{}: if or transaction is never finished", c.name).into());
+        }
+        match aux_depth(&c.words, [0; 2]) {
+            Ok([0, 0]) => {}
+            Ok(_) => {
+                return Err(format!("{}: auxiliary stack is not empty at the end", c.name).into());
+            }
+            Err(e) => return Err(format!("{}: {e}", c.name).into()),
+        }
+        Ok((c.name, c.immediate, self.optimize(c.words)))
+    }
+
+    /// Discard the definition in progress, if any.
+    pub fn abandon(&self) {
+        self.0.data.set(None);
+    }
+
+    /// Define a word from a finished list of operations.
+    ///
+    /// If the name is empty the word is returned instead.
+    pub fn define_ops(
+        &self,
+        dict: &Dictionary,
+        name: &str,
+        immediate: bool,
+        ops: Rc<[Op]>,
+    ) -> Option<Word> {
+        let x = self.compile(&ops);
+        let c = self.clone();
+        let f = move || c.aux_frame(|| x.iter().try_for_each(|x| (x)()));
+        // words using the auxiliary stacks need their own part of them, even when called directly
+        let inline = self.0.optimize.get()
+            && self.0.inline.get()
+            && ops.len() <= INLINE_LIMIT
+            && !uses_aux(&ops);
+        let x = if immediate {
+            with_imm(f)
+        } else if inline {
+            self.with_ops(ops.clone())
+        } else {
+            self.with(f)
+        };
+        if name.is_empty() {
+            return Some(x);
+        }
+        let kind = if immediate { ":!" } else { ":" };
+        self.log(kind, name, core::slice::from_ref(&x), move |w| w.ops(&ops));
+        dict.define(name, x);
+        None
+    }
+
+    /// Record a definition so it can be saved in an image.
+    ///
+    /// The words are given symbols referring to this entry.
+    pub fn log<F>(&self, kind: &str, name: &str, words: &[Word], save: F)
+    where
+        F: 'static + Fn(&mut Writer) -> super::Result<()>,
+    {
+        self.0.log.with(|l| {
+            for (i, w) in words.iter().enumerate() {
+                w.set_sym(Sym::Entry(l.len(), i));
+            }
+            l.push(Rc::new(Entry {
+                kind: kind.into(),
+                name: name.into(),
+                words: words.into(),
+                save: Box::new(save),
+            }));
         })
     }
 
-    fn cond_then(&self) -> super::Result<()> {
-        self.0.with(|c| {
-            let c = c.as_mut().unwrap();
-            let c = c.cond.as_mut().unwrap();
-            assert!(matches!(&c.stage, CondStage::Cond));
-            c.stage = CondStage::True;
+    /// Get all logged entries.
+    pub fn entries(&self) -> Vec<Rc<Entry>> {
+        self.0.log.with(|l| l.clone())
+    }
+
+    /// Register a function to redefine logged entries of the given kind.
+    pub fn loader<F>(&self, kind: &str, f: F)
+    where
+        F: 'static + Fn(&str, &mut Reader) -> super::Result<()>,
+    {
+        self.0.loaders.with(|l| l.insert(kind.into(), Rc::new(f)));
+    }
+
+    pub fn get_loader(&self, kind: &str) -> Option<Loader> {
+        self.0.loaders.with(|l| l.get(kind).cloned())
+    }
+
+    fn aux_len(&self) -> [usize; 2] {
+        [
+            self.0.aux_int.with(|v| v.len()),
+            self.0.aux_obj.with(|v| v.len()),
+        ]
+    }
+
+    fn aux_truncate(&self, len: [usize; 2]) {
+        self.0.aux_int.with(|v| v.truncate(len[0]));
+        self.0.aux_obj.with(|v| v.truncate(len[1]));
+    }
+
+    /// Run the body of a word with its own part of the auxiliary stacks,
+    /// which is discarded when it returns or fails.
+    pub fn aux_frame<F>(&self, f: F) -> super::Result<()>
+    where
+        F: FnOnce() -> super::Result<()>,
+    {
+        let (outer, base) = (self.0.aux_base.get(), self.aux_len());
+        self.0.aux_base.set(base);
+        let res = f();
+        self.aux_truncate(base);
+        self.0.aux_base.set(outer);
+        res
+    }
+
+    /// Fail if the running word would take a value from auxiliary stack `i` that it didn't put there.
+    fn aux_check(&self, i: usize) -> super::Result<()> {
+        if self.aux_len()[i] > self.0.aux_base.get()[i] {
             Ok(())
+        } else {
+            Err("auxiliary stack is empty".into())
+        }
+    }
+
+    /// Get the execution token of a word, which refers to it without looking it up by name.
+    ///
+    /// Tokens are logged, so they stay valid in images.
+    pub fn token(&self, word: &Word) -> super::Result<Object> {
+        if matches!(word.sym(), Sym::None) {
+            return Err("word has no stable name and can't be referenced".into());
+        }
+        let i = self
+            .0
+            .tokens
+            .with(|t| t.iter().position(|x| x.ptr_eq(word)));
+        let i = i.unwrap_or_else(|| self.add_token(word.clone()));
+        let mut data = vec![TOKEN];
+        write_usize(&mut data, i);
+        Ok(data.into())
+    }
+
+    fn add_token(&self, word: Word) -> usize {
+        let w = word.clone();
+        self.log("Token", "", &[], move |wr| wr.word(&w));
+        self.0.tokens.with(|t| {
+            t.push(word);
+            t.len() - 1
         })
     }
 
-    fn cond_else(&self) -> super::Result<()> {
-        self.0.with(|c| {
-            let c = c.as_mut().unwrap();
-            let c = c.cond.as_mut().unwrap();
-            assert!(matches!(&c.stage, CondStage::True));
-            c.stage = CondStage::False;
-            Ok(())
+    /// Get the word an execution token refers to.
+    pub fn token_word(&self, token: &Object) -> super::Result<Word> {
+        let err = || "object is not an execution token";
+        let ([TOKEN, data @ ..], []) = (token.data(), token.refs()) else {
+            return Err(err().into());
+        };
+        let mut data = data;
+        let i = read_usize(&mut data, "execution token")?;
+        if !data.is_empty() {
+            return Err(err().into());
+        }
+        Ok(self.0.tokens.with(|t| t.get(i).cloned()).ok_or_else(err)?)
+    }
+
+    /// Record how to undo a change to a cell if a transaction is running.
+    ///
+    /// Only the first change to a cell in a transaction is recorded.
+    pub fn touch<F, U>(&self, cell: *const (), undo: F)
+    where
+        F: FnOnce() -> U,
+        U: 'static + FnOnce(),
+    {
+        self.0.journal.with(|j| {
+            if let Some(j) = j.last_mut() {
+                j.entry(cell as usize).or_insert_with(|| Box::new(undo()));
+            }
         })
     }
 
-    fn cond_end(&self, stack: &Rc<Stack<BigInt>>) -> super::Result<()> {
-        let f = self.0.with(|cc| {
-            let c = cc.as_mut().unwrap();
-            let [cond, tru, fals] = c.cond.take().unwrap().finish()?;
-            let stack = stack.clone();
-            let f = move || {
-                cond.iter().try_for_each(|x| (x)())?;
-                (stack.pop()? != BigInt::ZERO)
-                    .then_some(&tru)
-                    .unwrap_or(&fals)
-                    .iter()
-                    .try_for_each(|x| (x)())
-            };
-            super::Result::Ok(if c.name.is_empty() {
-                *cc = None;
-                Some(f)
-            } else {
-                c.push(with_imm(f));
-                None
-            })
-        })?;
-        f.map(|f| (f)()).transpose().map(|_| ())
+    /// Start a condition or transaction, creating an anonymous definition if there is none.
+    fn frame_begin(&self, frame: Frame) -> super::Result<()> {
+        self.0.data.with(|c| {
+            let c = c.get_or_insert_with(|| CompilerData::new("", false));
+            c.frames.push(frame);
+        });
+        Ok(())
     }
 
-    fn cond_repeat(&self, stack: &Rc<Stack<BigInt>>) -> super::Result<()> {
-        let f = self.0.with(|cc| {
-            let c = cc.as_mut().unwrap();
-            let [cond, tru, fals] = c.cond.take().unwrap().finish()?;
-            let stack = stack.clone();
-            let f = move || {
-                while {
-                    cond.iter().try_for_each(|x| (x)())?;
-                    stack.pop()? != BigInt::ZERO
-                } {
-                    tru.iter().try_for_each(|x| (x)())?;
+    /// Move the innermost condition from one part to the next.
+    fn cond_next(&self, from: CondStage, to: CondStage, err: &str) -> super::Result<()> {
+        self.0
+            .data
+            .with(|c| match c.as_mut().and_then(|c| c.frames.last_mut()) {
+                Some(Frame::Cond(c)) if c.stage == from => {
+                    c.stage = to;
+                    Ok(())
                 }
-                fals.iter().try_for_each(|x| (x)())
+                _ => Err(err.into()),
+            })
+    }
+
+    /// Finish the innermost condition or transaction and add it to the enclosing one,
+    /// or run it immediately if it was used outside a definition.
+    fn frame_finish(&self, repeat: bool) -> super::Result<()> {
+        let frame = self.0.data.with(|c| {
+            let frames = &mut c.as_mut()?.frames;
+            let valid = match frames.last()? {
+                Frame::Cond(c) => !matches!(c.stage, CondStage::Cond),
+                Frame::Transaction(_) => !repeat,
             };
-            super::Result::Ok(if c.name.is_empty() {
+            valid.then(|| frames.pop().unwrap())
+        });
+        let op = match frame {
+            Some(Frame::Cond(c)) => {
+                let [cond, tru, fals] = [c.cond, c.tru, c.fals].map(|x| self.optimize(x).into());
+                Op::Cond(Rc::new(CondOps {
+                    repeat,
+                    cond,
+                    tru,
+                    fals,
+                }))
+            }
+            Some(Frame::Transaction(ops)) => Op::Transaction(self.optimize(ops).into()),
+            None if repeat => return Err("repeat without if and then".into()),
+            None => return Err("end without if and then or transaction".into()),
+        };
+        let op = self.0.data.with(|cc| {
+            let c = cc.as_mut().unwrap();
+            if c.name.is_empty() && c.frames.is_empty() {
                 *cc = None;
-                Some(f)
+                Some(op)
             } else {
-                c.push(with_imm(f));
+                c.push(op);
                 None
-            })
-        })?;
-        f.map(|f| (f)()).transpose().map(|_| ())
+            }
+        });
+        let Some(op) = op else {
+            return Ok(());
+        };
+        aux_depth(core::slice::from_ref(&op), [0; 2])?;
+        (self.compile_op(&op))()
     }
 
-    fn push(&self, word: Word) -> super::Result<()> {
-        self.0.with(|cc| {
+    fn push(&self, op: Op) -> super::Result<()> {
+        self.0.data.with(|cc| {
             let c = cc.as_mut().unwrap();
-            c.push(word)
+            c.push(op)
         });
         Ok(())
     }
 
     fn is_compiling(&self) -> bool {
-        self.0.with(|cc| cc.is_some())
+        self.0.data.with(|cc| cc.is_some())
     }
 }
 
 pub fn define<F>(
     read_word: Rc<F>,
     dict: &Dictionary,
-    stack: &Rc<Stack<BigInt>>,
+    stack: &Rc<Stack<Int>>,
     obj: &Rc<Stack<Object>>,
+    float: &Rc<Stack<f64>>,
 ) -> Compiler
 where
     F: 'static + Fn() -> super::Result<Option<String>>,
 {
-    let compiler = Compiler(Default::default());
+    let compiler = Compiler(Rc::new(CompilerState {
+        data: Default::default(),
+        int: stack.clone(),
+        obj: obj.clone(),
+        float: float.clone(),
+        optimize: Cell::new(true),
+        inline: Cell::new(true),
+        aux_int: Default::default(),
+        aux_obj: Default::default(),
+        aux_base: Default::default(),
+        log: Default::default(),
+        loaders: Default::default(),
+        journal: Default::default(),
+        tokens: Default::default(),
+    }));
+    let d = dict.clone();
+    let c = compiler.clone();
+    compiler.loader(":", move |name, r| {
+        c.define_ops(&d, name, false, r.ops()?);
+        Ok(())
+    });
+    let d = dict.clone();
+    let c = compiler.clone();
+    compiler.loader(":!", move |name, r| {
+        c.define_ops(&d, name, true, r.ops()?);
+        Ok(())
+    });
+    let c = compiler.clone();
+    compiler.loader("Token", move |_, r| {
+        c.add_token(r.word()?.0);
+        Ok(())
+    });
     let c = compiler.clone();
     let read_word2 = read_word.clone();
     dict.imm(":", move || {
-        assert!(c.0.take().is_none(), "todo: already compiling");
+        assert!(c.0.data.take().is_none(), "todo: already compiling");
         let name = read_word2()?.unwrap();
         assert!(!name.is_empty(), "todo: forbid empty names");
-        c.0.set(Some(CompilerData::new(&name, false)));
+        c.0.data.set(Some(CompilerData::new(&name, false)));
         Ok(())
     });
     let c = compiler.clone();
     let read_word2 = read_word.clone();
     dict.imm(":!", move || {
-        assert!(c.0.take().is_none(), "todo: already compiling");
+        assert!(c.0.data.take().is_none(), "todo: already compiling");
         let name = read_word2()?.unwrap();
         assert!(!name.is_empty(), "todo: forbid empty names");
-        c.0.set(Some(CompilerData::new(&name, true)));
+        c.0.data.set(Some(CompilerData::new(&name, true)));
         Ok(())
     });
     let c = compiler.clone();
     let d = dict.clone();
-    dict.imm(";", move || {
-        c.finish(&d).map(|x| (x)()).transpose().map(|_| ())
-    });
+    dict.imm(";", move || c.finish(&d)?.map_or(Ok(()), |x| (x)()));
+    fn aux<T: 'static + Clone>(
+        comp: &Compiler,
+        dict: &Dictionary,
+        prefix: &str,
+        (stack, aux, i): (&Rc<Stack<T>>, &Rc<Stack<T>>, usize),
+        hints: [Hint; 3],
+    ) {
+        let (s, a) = (stack.clone(), aux.clone());
+        let f = comp.with_hint(hints[0], move || a.push(s.pop()?));
+        dict.define(&format!("{prefix}>r"), f);
+        let (s, a, c) = (stack.clone(), aux.clone(), comp.clone());
+        let f = comp.with_hint(hints[1], move || {
+            c.aux_check(i)?;
+            s.push(a.pop()?)
+        });
+        dict.define(&format!("{prefix}r>"), f);
+        let (s, a, c) = (stack.clone(), aux.clone(), comp.clone());
+        let f = comp.with_hint(hints[2], move || {
+            c.aux_check(i)?;
+            let x = a.pop()?;
+            a.push(x.clone())?;
+            s.push(x)
+        });
+        dict.define(&format!("{prefix}r@"), f);
+    }
+    let hints = [Hint::IntToAux, Hint::IntFromAux, Hint::IntCopyAux];
+    let aux_int = compiler.0.aux_int.clone();
+    aux(&compiler, dict, "#", (stack, &aux_int, 0), hints);
+    let hints = [Hint::ObjToAux, Hint::ObjFromAux, Hint::ObjCopyAux];
+    let aux_obj = compiler.0.aux_obj.clone();
+    aux(&compiler, dict, "@", (obj, &aux_obj, 1), hints);
     let c = compiler.clone();
-    dict.imm("if", move || c.cond_begin());
+    dict.imm("if", move || c.frame_begin(Frame::Cond(Cond::default())));
     let c = compiler.clone();
-    dict.imm("then", move || c.cond_then());
+    dict.imm("then", move || {
+        c.cond_next(CondStage::Cond, CondStage::True, "then without if")
+    });
     let c = compiler.clone();
-    dict.imm("else", move || c.cond_else());
+    dict.imm("else", move || {
+        c.cond_next(CondStage::True, CondStage::False, "else without then")
+    });
     let c = compiler.clone();
-    let s = stack.clone();
-    dict.imm("end", move || c.cond_end(&s));
+    dict.imm("end", move || c.frame_finish(false));
     let c = compiler.clone();
-    let s = stack.clone();
-    dict.imm("repeat", move || c.cond_repeat(&s));
+    dict.imm("transaction", move || {
+        c.frame_begin(Frame::Transaction(vec![]))
+    });
+    let c = compiler.clone();
+    dict.imm("repeat", move || c.frame_finish(true));
     let c = compiler.clone();
     let d = dict.clone();
     let r = read_word.clone();
@@ -247,18 +813,36 @@ where
         let word = d
             .get(&word)
             .ok_or_else(|| format!("(?) word {word:?} not defined"))?;
-        c.push(word)
+        c.push(Op::Call(word, Hint::Opaque))
+    });
+    let (c, d, r, o) = (
+        compiler.clone(),
+        dict.clone(),
+        read_word.clone(),
+        obj.clone(),
+    );
+    dict.imm("&", move || {
+        let name = r()?.ok_or("expected word name")?;
+        let word = d
+            .get(&name)
+            .ok_or_else(|| format!("undefined word {name:?}"))?;
+        let token = c.token(&word)?;
+        if c.is_compiling() {
+            c.push(Op::Obj(token))
+        } else {
+            o.push(token)
+        }
     });
     let c = compiler.clone();
     let o = obj.clone();
     dict.define(
         "!begin",
         compiler.with(move || {
-            assert!(c.0.take().is_none(), "todo: already compiling");
+            assert!(c.0.data.take().is_none(), "todo: already compiling");
             let name = o.pop()?;
             assert!(!name.data().is_empty(), "todo: forbid empty names");
             let name = core::str::from_utf8(name.data()).unwrap();
-            c.0.set(Some(CompilerData::new(&name, false)));
+            c.0.data.set(Some(CompilerData::new(name, false)));
             Ok(())
         }),
     );
@@ -268,8 +852,7 @@ where
         "!integer",
         compiler.with(move || {
             let x = s.pop()?;
-            let s = s.clone();
-            c.push(Rc::new(move || s.push(x.clone())))
+            c.push(Op::Int(x))
         }),
     );
     let c = compiler.clone();
@@ -282,7 +865,7 @@ where
             let name = core::str::from_utf8(name.data()).unwrap();
             let word = d.get(name).unwrap();
             if c.is_compiling() {
-                c.push(word)
+                c.push(call_op(word))
             } else {
                 word()
             }
@@ -290,3 +873,130 @@ where
     );
     compiler
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Root, eval};
+
+    fn error(source: &str) -> String {
+        eval(source).err().unwrap().to_string()
+    }
+
+    #[test]
+    fn aux_frames() {
+        // a word can't take what its caller put there
+        let e = error(r##": peek "#r>" !call ; : f 1 #>r peek #r> ; f"##);
+        assert_eq!(e, "auxiliary stack is empty");
+        // values left by a failing word are discarded
+        let root = Root::new();
+        root.push_source(b": bad 1 #>r \"x\" @>r 0 @byte ; bad".into());
+        assert!(root.interpret().is_err());
+        assert_eq!(root.comp.aux_len(), [0, 0]);
+        let root = eval(": f #>r #r@ #r@ + #r> + ; 2 f 3 f").unwrap();
+        assert_eq!(root.int.with(|v| v.clone()), [6.into(), 9.into()]);
+    }
+
+    #[test]
+    fn aux_balance() {
+        let e = error(": f 1 #>r if 1 then #r> #drop else end 0 ;");
+        assert_eq!(e, "f: conditional changes the auxiliary stack");
+        let e = error(": f if 1 #>r 0 then end ;");
+        assert_eq!(e, "f: conditional changes the auxiliary stack");
+        let e = error("if 1 then 2 #>r end");
+        assert_eq!(e, "conditional changes the auxiliary stack");
+        let e = error(r##":! m "#>r" !call ; : k 1 m ;"##);
+        assert_eq!(e, "k: auxiliary stack is not empty at the end");
+        let root = eval(": f 1 #>r if #r@ then #r@ else end #r> ; f").unwrap();
+        assert_eq!(root.int.with(|v| v.clone()), [1.into(), 1.into()]);
+    }
+
+    #[test]
+    fn transactions() {
+        let root = Root::new();
+        root.push_source(
+            b"Var integer n 1 set:n 10 \"keep\"
+            transaction 2 set:n #drop @drop 3 \"x\" transaction 4 set:n end @drop @drop end"
+                .into(),
+        );
+        let e = root.interpret().unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "stack damned::script::object::Object is empty"
+        );
+        assert_eq!(root.int.with(|v| v.clone()), [10.into()]);
+        assert_eq!(root.obj.with(|v| v.clone()), ["keep".into()]);
+        root.push_source(b"n transaction 5 set:n 1 2 + end n".into());
+        root.interpret().unwrap();
+        assert_eq!(root.int.with(|v| v.clone()), [10, 1, 3, 5].map(Into::into));
+    }
+
+    #[test]
+    fn frames() {
+        assert_eq!(error(": f transaction 1 then ;"), "then without if");
+        assert_eq!(
+            error(": f transaction repeat ;"),
+            "repeat without if and then"
+        );
+        assert_eq!(
+            error(": f if 1 end ;"),
+            "end without if and then or transaction"
+        );
+        assert_eq!(error("end"), "end without if and then or transaction");
+        let e = error(": f if 1 then transaction end ;");
+        assert_eq!(e, "f: if or transaction is never finished");
+        let root = eval(": f if #dup then if 1 then 2 else 3 end else 4 end ; 1 f 0 f").unwrap();
+        assert_eq!(root.int.with(|v| v.clone()), [1, 2, 0, 4].map(Into::into));
+    }
+}
+
+#[cfg(test)]
+mod optimizer {
+    use super::super::eval;
+
+    /// Run a word with the optimizer disabled and enabled and compare the results.
+    fn same(body: &str) {
+        let run = |enable: u8| {
+            let source = format!("{enable} Sys Vm optimize : g {body} ; : f g ; f");
+            match eval(&source) {
+                Ok(root) => Ok((
+                    root.int.with(|v| v.clone()),
+                    root.obj.with(|v| v.clone()),
+                    root.float.with(|v| v.clone()),
+                )),
+                Err(e) => Err(e.to_string()),
+            }
+        };
+        assert_eq!(run(0), run(1), "{body}");
+    }
+
+    #[test]
+    fn equivalence() {
+        for body in [
+            "1 2 + 3 * 4 -",
+            "9223372036854775807 1 + 2 *",
+            "-9223372036854775808 -1 *",
+            "1 62 #bit:shl 1 63 #bit:shl 1 64 #bit:shl",
+            "-1 63 #bit:shl -2 63 #bit:shl",
+            "1 -1 #bit:shl",
+            "5 #dup #drop 6 #dup 7 #swap #swap",
+            "1 2 #swap 3 4 #swap -",
+            "\"a\" @dup @drop \"b\" @dup \"c\" @swap @swap",
+            "1 #>r #r> 2 #>r #r@ #r> +",
+            "\"x\" @>r @r> \"y\" @>r @r@ @r> @concat",
+            "1 if 2 3 + then 4 5 * else 6 end",
+            "0 if 1 then 2 3 #swap - else end",
+            "#drop 1",
+            "1 2 + @drop",
+            "1 #drop #drop",
+            "1.5 2 #dup #drop",
+            "#dup #drop",
+            "1 #swap #swap",
+            "@dup @drop",
+            "\"a\" @swap @swap",
+            "#>r #r>",
+            "@>r @r>",
+        ] {
+            same(body);
+        }
+    }
+}
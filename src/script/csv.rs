@@ -0,0 +1,173 @@
+use super::{Compiler, Dictionary, Int, Object, Stack};
+use std::rc::Rc;
+
+/// Parse a table into an object with the rows as refs, each with the fields as refs.
+pub fn parse(src: &[u8], delimiter: u8) -> super::Result<Object> {
+    let mut rows = vec![];
+    let mut row = vec![];
+    let mut field = vec![];
+    let mut line = 1;
+    let mut it = src.iter().copied().peekable();
+    // whether the current row has any content, so a final newline doesn't add a row
+    let mut started = false;
+    while let Some(c) = it.next() {
+        match c {
+            b'"' if field.is_empty() => {
+                let start = line;
+                loop {
+                    match it.next() {
+                        Some(b'"') if it.peek() == Some(&b'"') => {
+                            it.next();
+                            field.push(b'"');
+                        }
+                        Some(b'"') => break,
+                        Some(c) => {
+                            line += usize::from(c == b'\n');
+                            field.push(c);
+                        }
+                        None => {
+                            return Err(format!("line {start}: unterminated quoted field").into());
+                        }
+                    }
+                }
+                match it.peek() {
+                    None | Some(b'\r' | b'\n') => {}
+                    Some(c) if *c == delimiter => {}
+                    _ => return Err(format!("line {line}: text after quoted field").into()),
+                }
+            }
+            c if c == delimiter => row.push(Object::from(core::mem::take(&mut field))),
+            b'\r' if it.peek() == Some(&b'\n') => continue,
+            b'\n' => {
+                line += 1;
+                row.push(Object::from(core::mem::take(&mut field)));
+                rows.push(core::mem::take(&mut row).into_iter().collect());
+                started = false;
+                continue;
+            }
+            c => field.push(c),
+        }
+        started = true;
+    }
+    if started {
+        row.push(Object::from(field));
+        rows.push(row.into_iter().collect());
+    }
+    Ok(rows.into_iter().collect())
+}
+
+pub fn write(table: &Object, delimiter: u8) -> super::Result<Vec<u8>> {
+    let mut out = vec![];
+    for (n, row) in table.refs().iter().enumerate() {
+        // an empty line reads back as one empty field
+        if row.refs().is_empty() {
+            return Err(format!("row {} has no fields", n + 1).into());
+        }
+        for (i, field) in row.refs().iter().enumerate() {
+            if i > 0 {
+                out.push(delimiter);
+            }
+            let x = field.data();
+            if x.iter()
+                .any(|c| matches!(c, b'"' | b'\r' | b'\n') || *c == delimiter)
+            {
+                out.push(b'"');
+                for c in x {
+                    if *c == b'"' {
+                        out.push(b'"');
+                    }
+                    out.push(*c);
+                }
+                out.push(b'"');
+            } else {
+                out.extend_from_slice(x);
+            }
+        }
+        out.extend_from_slice(b"\r\n");
+    }
+    Ok(out)
+}
+
+pub fn define<F>(
+    comp: &Compiler,
+    dictionary: &Dictionary,
+    read_word: &Rc<F>,
+    int: &Rc<Stack<Int>>,
+    obj: &Rc<Stack<Object>>,
+) where
+    F: 'static + Fn() -> super::Result<Option<String>>,
+{
+    let (int1, obj1) = (int.clone(), obj.clone());
+    let (int2, obj2) = (int.clone(), obj.clone());
+    dictionary.dict(
+        "Csv",
+        read_word,
+        &[
+            (
+                "parse",
+                comp.with(move || {
+                    let d = u8::try_from(int1.pop()?)?;
+                    obj1.push(parse(obj1.pop()?.data(), d)?)
+                }),
+            ),
+            (
+                "write",
+                comp.with(move || {
+                    let d = u8::try_from(int2.pop()?)?;
+                    obj2.push(write(&obj2.pop()?, d)?.into())
+                }),
+            ),
+        ],
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(rows: &[&[&str]]) -> Object {
+        rows.iter()
+            .map(|row| row.iter().map(|x| Object::from(*x)).collect())
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let t = table(&[
+            &["a", "b,c", "say \"hi\""],
+            &["multi\nline", "", "crlf\r\nend"],
+            &["", "x", "\""],
+            &[""],
+            &["last"],
+        ]);
+        for d in [b',', b';', b'\t'] {
+            let out = write(&t, d).unwrap();
+            assert_eq!(parse(&out, d).unwrap(), t);
+        }
+        assert_eq!(
+            write(&t, b',').unwrap(),
+            b"a,\"b,c\",\"say \"\"hi\"\"\"\r\n\"multi\nline\",,\"crlf\r\nend\"\r\n,x,\"\"\"\"\r\n\r\nlast\r\n"
+        );
+        // no line reads back as a row without fields
+        let t = table(&[&["a"], &[], &["b"]]);
+        let e = write(&t, b',').unwrap_err();
+        assert_eq!(e.to_string(), "row 2 has no fields");
+        assert_eq!(write(&table(&[]), b',').unwrap(), b"");
+    }
+
+    #[test]
+    fn line_endings() {
+        let t = table(&[&["a", "b"], &["c", "d"]]);
+        assert_eq!(parse(b"a,b\nc,d", b',').unwrap(), t);
+        assert_eq!(parse(b"a,b\r\nc,d\r\n", b',').unwrap(), t);
+        assert_eq!(parse(b"", b',').unwrap(), table(&[]));
+        assert_eq!(parse(b"\n", b',').unwrap(), table(&[&[""]]));
+    }
+
+    #[test]
+    fn errors() {
+        let error = |src: &[u8]| parse(src, b',').unwrap_err().to_string();
+        assert_eq!(error(b"a\n\"b\nc"), "line 2: unterminated quoted field");
+        assert_eq!(error(b"a\n\"b\nc\"d"), "line 3: text after quoted field");
+    }
+}
@@ -0,0 +1,183 @@
+use super::{
+    Compiler, Dictionary, Object, Stack,
+    image::{read_usize, write_usize as usize},
+    object::{MAX_DEPTH, StorageId},
+};
+use std::{collections::HashMap, rc::Rc};
+
+const MAGIC: &[u8] = b"damned-object\0";
+const VERSION: usize = 1;
+
+/// Encode an object as [`MAGIC`], a version, the amount of nodes and the nodes.
+///
+/// Each node is its bytes and the indices of its refs, both prefixed with their length.
+/// Refs point to earlier nodes and the root is last.
+/// Subtrees that share storage are only encoded once.
+pub fn encode(obj: &Object) -> Vec<u8> {
+    fn f(obj: &Object, nodes: &mut HashMap<StorageId, usize>, buf: &mut Vec<u8>) -> usize {
+        if let Some(i) = nodes.get(&obj.storage_id()) {
+            return *i;
+        }
+        let refs = obj
+            .refs()
+            .iter()
+            .map(|x| f(x, nodes, buf))
+            .collect::<Vec<_>>();
+        usize(buf, obj.data().len());
+        buf.extend_from_slice(obj.data());
+        usize(buf, refs.len());
+        refs.into_iter().for_each(|i| usize(buf, i));
+        let i = nodes.len();
+        nodes.insert(obj.storage_id(), i);
+        i
+    }
+    let mut nodes = HashMap::new();
+    let mut body = vec![];
+    f(obj, &mut nodes, &mut body);
+    let mut buf = MAGIC.to_vec();
+    usize(&mut buf, VERSION);
+    usize(&mut buf, nodes.len());
+    buf.extend(body);
+    buf
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn usize(&mut self) -> super::Result<usize> {
+        read_usize(&mut self.0, "encoded object")
+    }
+
+    fn bytes(&mut self, n: usize) -> super::Result<&'a [u8]> {
+        if n > self.0.len() {
+            return Err("encoded object is truncated".into());
+        }
+        let (x, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(x)
+    }
+}
+
+pub fn decode(data: &[u8]) -> super::Result<Object> {
+    let data = data.strip_prefix(MAGIC).ok_or("not an encoded object")?;
+    let mut r = Reader(data);
+    let version = r.usize()?;
+    if version != VERSION {
+        return Err(format!("unsupported object encoding version {version}").into());
+    }
+    let n = r.usize()?;
+    // every node takes at least two bytes
+    if n == 0 || n > r.0.len() / 2 {
+        return Err("invalid node count in encoded object".into());
+    }
+    let mut nodes = Vec::<Object>::with_capacity(n);
+    let mut depths = Vec::with_capacity(n);
+    for _ in 0..n {
+        let len = r.usize()?;
+        let data = r.bytes(len)?.into();
+        let mut depth = 1;
+        let refs = (0..r.usize()?)
+            .map(|_| {
+                let i = r.usize()?;
+                let x = nodes
+                    .get(i)
+                    .ok_or_else(|| format!("invalid ref {i} in encoded object"))?;
+                depth = depth.max(depths[i] + 1);
+                Ok(x.clone())
+            })
+            .collect::<super::Result<_>>()?;
+        if depth > MAX_DEPTH {
+            return Err("encoded object is nested too deeply".into());
+        }
+        nodes.push(Object::new(data, refs));
+        depths.push(depth);
+    }
+    if !r.0.is_empty() {
+        return Err("trailing data after encoded object".into());
+    }
+    Ok(nodes.pop().unwrap())
+}
+
+pub fn define(comp: &Compiler, dict: &Dictionary, obj: &Rc<Stack<Object>>) {
+    let s = obj.clone();
+    dict.define(
+        "@encode",
+        comp.with(move || s.push(encode(&s.pop()?).into())),
+    );
+    let s = obj.clone();
+    dict.define(
+        "@decode",
+        comp.with(move || s.push(decode(s.pop()?.data())?)),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Object {
+        let leaf = Object::from("leaf");
+        let pair = Object::from([leaf.clone(), Object::from("\0\u{ff}")]);
+        Object::new(b"root".as_slice().into(), [pair.clone(), pair, leaf].into())
+    }
+
+    #[test]
+    fn round_trip() {
+        for x in [Object::default(), Object::from("abc"), sample()] {
+            assert_eq!(decode(&encode(&x)).unwrap(), x);
+        }
+    }
+
+    #[test]
+    fn shared_subtrees() {
+        let x = sample();
+        let copy = Object::from([Object::from("leaf"), Object::from("\0\u{ff}")]);
+        let y = Object::new(
+            b"root".as_slice().into(),
+            [copy.clone(), copy, "leaf".into()].into(),
+        );
+        assert_eq!(x, y);
+        assert!(encode(&x).len() < encode(&y).len());
+    }
+
+    #[test]
+    fn truncated() {
+        let data = encode(&sample());
+        for n in 0..data.len() {
+            assert!(decode(&data[..n]).is_err());
+        }
+    }
+
+    #[test]
+    fn garbage() {
+        let mut data = encode(&sample());
+        data.push(0);
+        let e = decode(&data).unwrap_err();
+        assert_eq!(e.to_string(), "trailing data after encoded object");
+        let mut data = MAGIC.to_vec();
+        // one node with a ref to itself
+        data.extend([VERSION as u8, 1, 0, 1, 0]);
+        let e = decode(&data).unwrap_err();
+        assert_eq!(e.to_string(), "invalid ref 0 in encoded object");
+        let mut data = MAGIC.to_vec();
+        data.extend([VERSION as u8].iter().chain(&[0xff; 16]));
+        let e = decode(&data).unwrap_err();
+        assert_eq!(e.to_string(), "integer in encoded object is too large");
+        assert!(decode(b"garbage").is_err());
+    }
+
+    #[test]
+    fn deep() {
+        let n = 300_000;
+        let mut data = MAGIC.to_vec();
+        usize(&mut data, VERSION);
+        usize(&mut data, n);
+        data.extend([0, 0]);
+        for i in 0..n - 1 {
+            data.extend([0, 1]);
+            usize(&mut data, i);
+        }
+        let e = decode(&data).unwrap_err();
+        assert_eq!(e.to_string(), "encoded object is nested too deeply");
+    }
+}
@@ -0,0 +1,178 @@
+use super::{Compiler, Dictionary, Int, Object, Stack};
+use num::{BigInt, FromPrimitive, ToPrimitive};
+use std::rc::Rc;
+
+/// Parse a float literal, which must have a fraction or an exponent to not be an integer.
+fn from_string(s: &str) -> Option<f64> {
+    let b = s.as_bytes();
+    let float = b
+        .iter()
+        .all(|c| c.is_ascii_digit() || b"+-.eE_".contains(c))
+        && b.iter().any(u8::is_ascii_digit)
+        && b.iter().any(|c| b".eE".contains(c));
+    float.then(|| s.replace('_', "").parse().ok()).flatten()
+}
+
+pub fn define(
+    comp: &Compiler,
+    dict: &Dictionary,
+    int: &Rc<Stack<Int>>,
+    obj: &Rc<Stack<Object>>,
+    stack: &Rc<Stack<f64>>,
+) {
+    let f = |name: &str, f: fn(f64) -> f64| {
+        let s = stack.clone();
+        dict.define(name, comp.with(move || s.push((f)(s.pop()?))));
+    };
+    f("%neg", |x| -x);
+    f("%abs", f64::abs);
+    f("%sqrt", f64::sqrt);
+    f("%exp", f64::exp);
+    f("%ln", f64::ln);
+    f("%log10", f64::log10);
+    f("%sin", f64::sin);
+    f("%cos", f64::cos);
+    f("%tan", f64::tan);
+    f("%asin", f64::asin);
+    f("%acos", f64::acos);
+    f("%atan", f64::atan);
+    f("%floor", f64::floor);
+    f("%ceil", f64::ceil);
+    f("%round", f64::round);
+    f("%trunc", f64::trunc);
+    let g = |name: &str, f: fn(f64, f64) -> f64| {
+        let s = stack.clone();
+        dict.define(name, comp.with(move || s.op2to1(|x, y| Ok((f)(x, y)))));
+    };
+    g("%+", |x, y| x + y);
+    g("%-", |x, y| x - y);
+    g("%*", |x, y| x * y);
+    g("%/", |x, y| x / y);
+    g("%mod", f64::rem_euclid);
+    g("%pow", f64::powf);
+    g("%atan2", f64::atan2);
+    g("%min", f64::min);
+    g("%max", f64::max);
+    let h = |name: &str, f: fn(&f64, &f64) -> bool| {
+        let (s, int) = (stack.clone(), int.clone());
+        dict.define(
+            name,
+            comp.with(move || {
+                let y = s.pop()?;
+                let x = s.pop()?;
+                int.push((f)(&x, &y).into())
+            }),
+        );
+    };
+    h("%=", f64::eq);
+    h("%<>", f64::ne);
+    h("%<", f64::lt);
+    h("%>", f64::gt);
+    h("%<=", f64::le);
+    h("%>=", f64::ge);
+    let s = stack.clone();
+    dict.define(
+        "%dup",
+        comp.with(move || {
+            let x = s.pop()?;
+            s.push(x)?;
+            s.push(x)
+        }),
+    );
+    let s = stack.clone();
+    dict.define("%drop", comp.with(move || s.pop().map(|_| ())));
+    let s = stack.clone();
+    dict.define(
+        "%swap",
+        comp.with(move || {
+            let y = s.pop()?;
+            let x = s.pop()?;
+            s.push(y)?;
+            s.push(x)
+        }),
+    );
+    super::define_shuffles(comp, dict, "%", stack, int);
+    let (s, int2) = (stack.clone(), int.clone());
+    dict.define(
+        "%fromint",
+        comp.with(move || {
+            let x = int2.pop()?.to_bigint();
+            // too large integers round to infinity
+            let y = x.to_f64().filter(|y| y.is_finite());
+            s.push(y.ok_or_else(|| format!("{x} can't be a float"))?)
+        }),
+    );
+    let (s, int2) = (stack.clone(), int.clone());
+    dict.define(
+        "%toint",
+        comp.with(move || {
+            let x = s.pop()?;
+            // truncates toward zero
+            let x = BigInt::from_f64(x).ok_or_else(|| format!("{x} can't be an integer"))?;
+            int2.push(x.into())
+        }),
+    );
+    let (s, obj2) = (stack.clone(), obj.clone());
+    dict.define(
+        "%fromstring",
+        comp.with(move || {
+            let x = obj2.pop()?;
+            let x = <&str>::try_from(&x)?.trim();
+            s.push(x.parse().map_err(|_| format!("{x:?} is not a float"))?)
+        }),
+    );
+    let (s, obj2) = (stack.clone(), obj.clone());
+    dict.define(
+        "%tostring",
+        comp.with(move || obj2.push(s.pop()?.to_string().into())),
+    );
+    let comp = comp.clone();
+    dict.push_alt(move |name| from_string(name).map(|x| comp.float(x)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::eval;
+    use super::*;
+
+    fn floats(source: &str) -> Vec<f64> {
+        eval(source).unwrap().float.with(|v| v.clone())
+    }
+
+    fn ints(source: &str) -> Vec<Int> {
+        eval(source).unwrap().int.with(|v| v.clone())
+    }
+
+    fn error(source: &str) -> String {
+        eval(source).err().unwrap().to_string()
+    }
+
+    #[test]
+    fn literals() {
+        let root = eval("1 -2 1_000").unwrap();
+        assert_eq!(root.int.with(|v| v.clone()), [1, -2, 1000].map(Int::from));
+        assert!(root.float.with(|v| v.is_empty()));
+        let v = floats("1.0 1e3 -2.5 1.5e-3 .5 1E2 1_000.0");
+        assert_eq!(v, [1.0, 1e3, -2.5, 1.5e-3, 0.5, 100.0, 1000.0]);
+        assert_eq!(from_string("e"), None);
+        assert_eq!(from_string("."), None);
+        assert_eq!(from_string("1.0.0"), None);
+        assert_eq!(from_string("inf"), None);
+        assert_eq!(from_string("NaN"), None);
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(floats("3 %fromint -7 %fromint"), [3.0, -7.0]);
+        let v = ints("2.7 %toint -2.7 %toint 1e20 %toint -0.0 %toint");
+        let big = Int::from(BigInt::from(10).pow(20));
+        assert_eq!(v, [2.into(), (-2).into(), big, 0.into()]);
+        assert_eq!(error("0.0 0.0 %/ %toint"), "NaN can't be an integer");
+        assert_eq!(error("1.0 0.0 %/ %toint"), "inf can't be an integer");
+        assert_eq!(error("-1.0 0.0 %/ %toint"), "-inf can't be an integer");
+        let e = error("1 1100 #bit:shl %fromint");
+        assert!(e.ends_with(" can't be a float"), "{e}");
+        assert_eq!(floats("\" 2.5 \" %fromstring"), [2.5]);
+        assert_eq!(error("\"x\" %fromstring"), "\"x\" is not a float");
+    }
+}
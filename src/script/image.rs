@@ -0,0 +1,522 @@
+use super::{
+    Compiler, Dictionary, Int, Object, Stack, Streams, Word,
+    compiler::{CondOps, Hint, Op},
+    object::MAX_DEPTH,
+};
+use num::BigInt;
+use std::rc::Rc;
+
+const MAGIC: &[u8] = b"damned\0";
+const VERSION: usize = 2;
+
+/// Deepest nesting of conditions and transactions in loaded operations.
+///
+/// Lower than [`MAX_DEPTH`], as compiling and running them recurses with bigger frames.
+const MAX_OPS_DEPTH: usize = 256;
+
+pub type Loader = Rc<dyn Fn(&str, &mut Reader) -> super::Result<()>>;
+pub type Saver = Box<dyn Fn(&mut Writer) -> super::Result<()>>;
+
+/// Stable name of a word, so it can be found again after a restart.
+#[derive(Clone, Default)]
+pub enum Sym {
+    /// The word can't be saved.
+    #[default]
+    None,
+    /// Word defined by the host, by full path.
+    Native(Rc<str>),
+    /// Word `.1` of logged entry `.0`.
+    Entry(usize, usize),
+}
+
+/// A logged definition.
+pub struct Entry {
+    pub kind: Box<str>,
+    pub name: Box<str>,
+    pub words: Box<[Word]>,
+    pub save: Saver,
+}
+
+pub struct Writer<'a> {
+    buf: Vec<u8>,
+    comp: &'a Compiler,
+    dict: &'a Dictionary,
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+    comp: &'a Compiler,
+    dict: &'a Dictionary,
+}
+
+/// Values that can be stored in an image.
+pub trait Persist: Sized {
+    fn save(&self, w: &mut Writer);
+    fn load(r: &mut Reader) -> super::Result<Self>;
+}
+
+/// Append an integer as unsigned LEB128.
+pub fn write_usize(buf: &mut Vec<u8>, mut x: usize) {
+    while x >= 0x80 {
+        buf.push(x as u8 | 0x80);
+        x >>= 7;
+    }
+    buf.push(x as u8);
+}
+
+/// Take an unsigned LEB128 integer from the start of `data`, which is called `what` in errors.
+pub fn read_usize(data: &mut &[u8], what: &str) -> super::Result<usize> {
+    let mut x = 0usize;
+    for shift in (0..usize::BITS).step_by(7) {
+        let [b, rest @ ..] = *data else {
+            return Err(format!("{what} is truncated").into());
+        };
+        *data = rest;
+        let bits = usize::from(b & 0x7f);
+        // bits shifted out of the top would be lost
+        x |= bits
+            .checked_shl(shift)
+            .filter(|y| y >> shift == bits)
+            .ok_or_else(|| format!("integer in {what} is too large"))?;
+        if b & 0x80 == 0 {
+            return Ok(x);
+        }
+    }
+    Err(format!("integer in {what} is too large").into())
+}
+
+impl Writer<'_> {
+    pub fn usize(&mut self, x: usize) {
+        write_usize(&mut self.buf, x)
+    }
+
+    pub fn bytes(&mut self, x: &[u8]) {
+        self.usize(x.len());
+        self.buf.extend_from_slice(x);
+    }
+
+    pub fn str(&mut self, x: &str) {
+        self.bytes(x.as_bytes())
+    }
+
+    pub fn ops(&mut self, ops: &[Op]) -> super::Result<()> {
+        self.usize(ops.len());
+        ops.iter().try_for_each(|op| match op {
+            Op::Int(x) => {
+                self.usize(0);
+                x.save(self);
+                Ok(())
+            }
+            Op::Obj(x) => {
+                self.usize(1);
+                x.save(self);
+                Ok(())
+            }
+            Op::Float(x) => {
+                self.usize(5);
+                x.save(self);
+                Ok(())
+            }
+            Op::Call(w, _) => {
+                self.usize(2);
+                self.word(w)
+            }
+            Op::Cond(c) => {
+                self.usize(3);
+                self.usize(c.repeat.into());
+                [&c.cond, &c.tru, &c.fals]
+                    .iter()
+                    .try_for_each(|x| self.ops(x))
+            }
+            Op::Transaction(ops) => {
+                self.usize(4);
+                self.ops(ops)
+            }
+        })
+    }
+
+    pub fn word(&mut self, word: &Word) -> super::Result<()> {
+        let w = match word.sym() {
+            Sym::None => return Err("word has no stable name and can't be saved".into()),
+            Sym::Native(path) => {
+                self.usize(0);
+                self.str(&path);
+                self.dict.native(&path)
+            }
+            Sym::Entry(n, i) => {
+                self.usize(1);
+                self.usize(n);
+                self.usize(i);
+                self.comp.entries().get(n).map(|e| e.words[i].clone())
+            }
+        };
+        let w = w.ok_or("word is not reachable")?;
+        self.usize((!w.ptr_eq(word)).into());
+        Ok(())
+    }
+}
+
+impl<'a> Reader<'a> {
+    pub fn usize(&mut self) -> super::Result<usize> {
+        read_usize(&mut self.data, "image")
+    }
+
+    pub fn bytes(&mut self) -> super::Result<&'a [u8]> {
+        let n = self.usize()?;
+        if n > self.data.len() {
+            return Err("image is truncated".into());
+        }
+        let (x, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(x)
+    }
+
+    /// Take all remaining data.
+    pub fn rest(&mut self) -> &'a [u8] {
+        core::mem::take(&mut self.data)
+    }
+
+    pub fn str(&mut self) -> super::Result<&'a str> {
+        Ok(core::str::from_utf8(self.bytes()?)?)
+    }
+
+    pub fn ops(&mut self) -> super::Result<Rc<[Op]>> {
+        self.nested_ops(MAX_OPS_DEPTH)
+    }
+
+    fn nested_ops(&mut self, depth: usize) -> super::Result<Rc<[Op]>> {
+        let depth = depth
+            .checked_sub(1)
+            .ok_or("operations in image are nested too deeply")?;
+        let n = self.usize()?;
+        let mut ops = vec![];
+        for _ in 0..n {
+            ops.push(match self.usize()? {
+                0 => Op::Int(Int::load(self)?),
+                1 => Op::Obj(Object::load(self)?),
+                2 => {
+                    let (w, h) = self.word()?;
+                    Op::Call(w, h)
+                }
+                3 => {
+                    let repeat = self.usize()? != 0;
+                    let [cond, tru, fals] = [
+                        self.nested_ops(depth)?,
+                        self.nested_ops(depth)?,
+                        self.nested_ops(depth)?,
+                    ];
+                    Op::Cond(Rc::new(CondOps {
+                        repeat,
+                        cond: cond.iter().cloned().collect(),
+                        tru: tru.iter().cloned().collect(),
+                        fals: fals.iter().cloned().collect(),
+                    }))
+                }
+                4 => Op::Transaction(self.nested_ops(depth)?),
+                5 => Op::Float(f64::load(self)?),
+                x => return Err(format!("invalid operation {x} in image").into()),
+            });
+        }
+        Ok(ops.into())
+    }
+
+    pub fn word(&mut self) -> super::Result<(Word, Hint)> {
+        let w = match self.usize()? {
+            0 => {
+                let path = self.str()?;
+                self.dict
+                    .native(path)
+                    .ok_or_else(|| format!("native word {path:?} doesn't exist"))?
+            }
+            1 => {
+                let (n, i) = (self.usize()?, self.usize()?);
+                self.comp
+                    .entries()
+                    .get(n)
+                    .and_then(|e| e.words.get(i).cloned())
+                    .ok_or_else(|| format!("word {i} of entry {n} doesn't exist"))?
+            }
+            x => return Err(format!("invalid word kind {x} in image").into()),
+        };
+        match self.usize()? {
+            0 => Ok((w, Hint::Opaque)),
+            _ => match w.0.ops.as_deref() {
+                Some([Op::Call(w, h)]) => Ok((w.clone(), *h)),
+                _ => Err("word can't be referenced in image".into()),
+            },
+        }
+    }
+}
+
+/// Read a word written with [`Writer::word`].
+///
+/// Useful to resolve a word only once the entries defined after it are loaded.
+pub fn read_word(data: &[u8], comp: &Compiler, dict: &Dictionary) -> super::Result<(Word, Hint)> {
+    Reader { data, comp, dict }.word()
+}
+
+impl Persist for Int {
+    fn save(&self, w: &mut Writer) {
+        w.bytes(&self.to_bigint().to_signed_bytes_le())
+    }
+
+    fn load(r: &mut Reader) -> super::Result<Self> {
+        Ok(BigInt::from_signed_bytes_le(r.bytes()?).into())
+    }
+}
+
+impl Persist for f64 {
+    fn save(&self, w: &mut Writer) {
+        w.bytes(&self.to_le_bytes())
+    }
+
+    fn load(r: &mut Reader) -> super::Result<Self> {
+        let x = r
+            .bytes()?
+            .try_into()
+            .map_err(|_| "float in image has the wrong size")?;
+        Ok(f64::from_le_bytes(x))
+    }
+}
+
+impl Persist for Object {
+    fn save(&self, w: &mut Writer) {
+        w.bytes(self.data());
+        w.usize(self.refs().len());
+        self.refs().iter().for_each(|x| x.save(w));
+    }
+
+    fn load(r: &mut Reader) -> super::Result<Self> {
+        load_object(r, MAX_DEPTH)
+    }
+}
+
+fn load_object(r: &mut Reader, depth: usize) -> super::Result<Object> {
+    let depth = depth
+        .checked_sub(1)
+        .ok_or("object in image is nested too deeply")?;
+    let data = r.bytes()?.into();
+    let n = r.usize()?;
+    let mut refs = vec![];
+    for _ in 0..n {
+        refs.push(load_object(r, depth)?);
+    }
+    Ok(Object::new(data, refs.into()))
+}
+
+/// Take the remaining source of the current stream without consuming it.
+pub fn rest_of_source(streams: &Streams) -> Vec<u8> {
+    streams.with(|s| {
+        let Some(x) = s.last_mut() else {
+            return vec![];
+        };
+        let rest = core::iter::from_fn(&mut *x).collect::<Vec<_>>();
+        let mut it = rest.clone().into_iter();
+        *x = Box::new(move || it.next());
+        rest
+    })
+}
+
+/// Serialize all definitions, stacks and the given remaining source.
+pub fn save(
+    comp: &Compiler,
+    dict: &Dictionary,
+    int: &Stack<Int>,
+    obj: &Stack<Object>,
+    float: &Stack<f64>,
+    source: &[u8],
+) -> super::Result<Vec<u8>> {
+    let mut w = Writer {
+        buf: MAGIC.into(),
+        comp,
+        dict,
+    };
+    w.usize(VERSION);
+    let entries = comp.entries();
+    w.usize(entries.len());
+    for e in entries {
+        w.str(&e.kind);
+        w.str(&e.name);
+        let mut sub = Writer {
+            buf: vec![],
+            comp,
+            dict,
+        };
+        (e.save)(&mut sub).map_err(|x| format!("can't save {:?}: {x}", e.name))?;
+        w.bytes(&sub.buf);
+    }
+    int.with(|v| {
+        w.usize(v.len());
+        v.iter().for_each(|x| x.save(&mut w))
+    });
+    obj.with(|v| {
+        w.usize(v.len());
+        v.iter().for_each(|x| x.save(&mut w))
+    });
+    float.with(|v| {
+        w.usize(v.len());
+        v.iter().for_each(|x| x.save(&mut w))
+    });
+    w.bytes(source);
+    Ok(w.buf)
+}
+
+/// Redefine everything in an image and restore the stacks.
+///
+/// Returns the source that remained when the image was saved.
+pub fn load(
+    image: &[u8],
+    comp: &Compiler,
+    dict: &Dictionary,
+    int: &Stack<Int>,
+    obj: &Stack<Object>,
+    float: &Stack<f64>,
+) -> super::Result<Vec<u8>> {
+    let data = image.strip_prefix(MAGIC).ok_or("not an image")?;
+    let mut r = Reader { data, comp, dict };
+    let version = r.usize()?;
+    if version != VERSION {
+        return Err(format!("unsupported image version {version}").into());
+    }
+    for _ in 0..r.usize()? {
+        let kind = r.str()?;
+        let name = r.str()?.to_string();
+        let f = comp
+            .get_loader(kind)
+            .ok_or_else(|| format!("unknown entry kind {kind:?}"))?;
+        let data = r.bytes()?;
+        let mut sub = Reader { data, comp, dict };
+        (f)(&name, &mut sub).map_err(|x| format!("can't load {name:?}: {x}"))?;
+    }
+    for _ in 0..r.usize()? {
+        int.push(Int::load(&mut r)?)?;
+    }
+    for _ in 0..r.usize()? {
+        obj.push(Object::load(&mut r)?)?;
+    }
+    for _ in 0..r.usize()? {
+        float.push(f64::load(&mut r)?)?;
+    }
+    Ok(r.bytes()?.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Root, eval};
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let a = eval(
+            r#"
+            Var integer n
+            : twice #dup + ;
+            : step n twice set:n ;
+            21 set:n
+            7 "abc" "y" @intoref "x" @swap @intoref @concat 1.5
+            "#,
+        )
+        .unwrap();
+        let image = save(&a.comp, &a.dictionary, &a.int, &a.obj, &a.float, b"step n").unwrap();
+        let b = Root::new();
+        let source = load(&image, &b.comp, &b.dictionary, &b.int, &b.obj, &b.float).unwrap();
+        assert_eq!(source, b"step n");
+        assert_eq!(a.int.with(|v| v.clone()), b.int.with(|v| v.clone()));
+        assert_eq!(a.obj.with(|v| v.clone()), b.obj.with(|v| v.clone()));
+        assert_eq!(a.float.with(|v| v.clone()), b.float.with(|v| v.clone()));
+        b.push_source(source);
+        b.interpret().unwrap();
+        assert_eq!(b.int.pop().unwrap(), 42.into());
+    }
+
+    #[test]
+    fn tokens() {
+        let a = eval(
+            r#"
+            Var word handler
+            : hello "hello" ;
+            & hello set:handler
+            : twice @dup @concat ;
+            : greet "ab" @intoref & twice @map ;
+            "#,
+        )
+        .unwrap();
+        let image = save(&a.comp, &a.dictionary, &a.int, &a.obj, &a.float, b"").unwrap();
+        let b = Root::new();
+        load(&image, &b.comp, &b.dictionary, &b.int, &b.obj, &b.float).unwrap();
+        b.push_source(b"handler greet".into());
+        b.interpret().unwrap();
+        assert_eq!(b.obj.pop().unwrap().refs(), ["abab".into()]);
+        assert_eq!(b.obj.pop().unwrap(), "hello".into());
+    }
+
+    #[test]
+    fn truncated() {
+        let a = eval("1 2 \"abc\"").unwrap();
+        let image = save(&a.comp, &a.dictionary, &a.int, &a.obj, &a.float, b"").unwrap();
+        for n in 0..image.len() {
+            let b = Root::new();
+            assert!(
+                load(
+                    &image[..n],
+                    &b.comp,
+                    &b.dictionary,
+                    &b.int,
+                    &b.obj,
+                    &b.float
+                )
+                .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn deep_object() {
+        let mut image = MAGIC.to_vec();
+        // version, no entries, no integers and one object
+        image.extend([VERSION as u8, 0, 0, 1]);
+        // each object has no data and one ref
+        image.extend([0, 1].repeat(MAX_DEPTH * 10));
+        let b = Root::new();
+        let e = load(&image, &b.comp, &b.dictionary, &b.int, &b.obj, &b.float).unwrap_err();
+        assert_eq!(e.to_string(), "object in image is nested too deeply");
+    }
+
+    #[test]
+    fn deep_ops() {
+        let mut image = MAGIC.to_vec();
+        // version and one entry
+        image.extend([VERSION as u8, 1, 1, b':', 1, b'x']);
+        // each list has one transaction with the next list inside
+        let ops = [1, 4].repeat(MAX_OPS_DEPTH * 10);
+        write_usize(&mut image, ops.len());
+        image.extend(ops);
+        let b = Root::new();
+        let e = load(&image, &b.comp, &b.dictionary, &b.int, &b.obj, &b.float).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "can't load \"x\": operations in image are nested too deeply"
+        );
+    }
+
+    #[test]
+    fn leb128() {
+        let mut buf = vec![];
+        for x in [0, 1, 0x7f, 0x80, 0x3fff, 0x4000, usize::MAX] {
+            buf.clear();
+            write_usize(&mut buf, x);
+            let mut data = &buf[..];
+            assert_eq!(read_usize(&mut data, "image").unwrap(), x);
+            assert!(data.is_empty());
+        }
+        // one bit more than fits
+        let mut data = &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x03][..];
+        let e = read_usize(&mut data, "image").unwrap_err();
+        assert_eq!(e.to_string(), "integer in image is too large");
+        let mut data = &[0x80; 11][..];
+        assert!(read_usize(&mut data, "image").is_err());
+        let mut data = &[0x80][..];
+        let e = read_usize(&mut data, "image").unwrap_err();
+        assert_eq!(e.to_string(), "image is truncated");
+    }
+}
@@ -0,0 +1,179 @@
+use super::{Compiler, Dictionary, Int, Object, Stack};
+use core::fmt::Write;
+use std::rc::Rc;
+
+/// Render an object as source that pushes an equal object.
+pub fn source(obj: &Object, out: &mut String) {
+    let mut refs = obj.refs().iter();
+    if obj.data().is_empty()
+        && let Some(x) = refs.next()
+    {
+        source(x, out);
+        out.push_str(" @intoref");
+    } else {
+        data(obj.data(), out);
+    }
+    for x in refs {
+        out.push(' ');
+        source(x, out);
+        out.push_str(" @intoref @concat");
+    }
+}
+
+/// Render bytes as string literals where possible and `@intobyte` otherwise.
+fn data(x: &[u8], out: &mut String) {
+    // string literals can't contain quotes and only support the `\n` escape
+    let literal =
+        |c: u8| c == b'\n' || (c.is_ascii() && !c.is_ascii_control() && !b"\"'`\\".contains(&c));
+    if x.is_empty() {
+        out.push_str("\"\"");
+    }
+    for (i, run) in x.chunk_by(|a, b| literal(*a) && literal(*b)).enumerate() {
+        if i > 0 {
+            out.push(' ');
+        }
+        if literal(run[0]) {
+            out.push('"');
+            for c in run {
+                match c {
+                    b'\n' => out.push_str("\\n"),
+                    c => out.push(char::from(*c)),
+                }
+            }
+            out.push('"');
+        } else {
+            write!(out, "{} @intobyte", run[0]).unwrap();
+        }
+        if i > 0 {
+            out.push_str(" @concat");
+        }
+    }
+}
+
+/// Render a float as source, computing the values that have no literal.
+fn float_source(x: f64, out: &mut String) {
+    if x.is_nan() {
+        out.push_str("0.0 0.0 %/");
+    } else if x.is_infinite() {
+        write!(out, "{}1.0 0.0 %/", if x < 0.0 { "-" } else { "" }).unwrap();
+    } else {
+        // debug formatting keeps the fraction, so the value reads back as a float
+        write!(out, "{x:?}").unwrap();
+    }
+}
+
+/// Print text, translating newlines if the terminal is in raw mode.
+fn print(s: &str) -> super::Result<()> {
+    use std::io::Write;
+    let mut out = std::io::stdout();
+    if crossterm::terminal::is_raw_mode_enabled()? {
+        out.write_all(s.replace('\n', "\r\n").as_bytes())?;
+    } else {
+        out.write_all(s.as_bytes())?;
+    }
+    Ok(out.flush()?)
+}
+
+pub fn define(
+    comp: &Compiler,
+    dict: &Dictionary,
+    int: &Rc<Stack<Int>>,
+    obj: &Rc<Stack<Object>>,
+    float: &Rc<Stack<f64>>,
+) {
+    let (int2, obj2, float2) = (int.clone(), obj.clone(), float.clone());
+    dict.define(
+        ".s",
+        comp.with(move || {
+            let mut s = String::new();
+            int2.with(|v| {
+                write!(s, "#<{}>", v.len())?;
+                v.iter().try_for_each(|x| write!(s, " {x}"))
+            })?;
+            obj2.with(|v| {
+                write!(s, "\n@<{}>", v.len())?;
+                v.iter().for_each(|x| {
+                    s.push(' ');
+                    source(x, &mut s);
+                });
+                Ok::<_, core::fmt::Error>(())
+            })?;
+            float2.with(|v| {
+                write!(s, "\n%<{}>", v.len())?;
+                v.iter().for_each(|x| {
+                    s.push(' ');
+                    float_source(*x, &mut s);
+                });
+                s.push('\n');
+                Ok::<_, core::fmt::Error>(())
+            })?;
+            print(&s)
+        }),
+    );
+    let obj2 = obj.clone();
+    dict.define(
+        "@.",
+        comp.with(move || {
+            let mut s = String::new();
+            source(&obj2.pop()?, &mut s);
+            s.push('\n');
+            print(&s)
+        }),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::eval;
+    use super::*;
+
+    fn obj<const N: usize>(data: &[u8], refs: [Object; N]) -> Object {
+        Object::new(data.into(), refs.into())
+    }
+
+    #[test]
+    fn round_trip() {
+        let leaf = obj(b"a\"b'c`d\\e\nf\tg", []);
+        let binary = obj(&[0, 0xff, b'x', 0x80, b'\r'], []);
+        let nested = obj(b"", [obj(b"", [leaf.clone()]), obj(b"", [])]);
+        let cases = [
+            obj(b"", []),
+            leaf.clone(),
+            binary.clone(),
+            obj(b"", [leaf.clone(), binary.clone()]),
+            obj("caf\u{e9}".as_bytes(), [nested.clone(), binary, nested]),
+        ];
+        for x in cases {
+            let mut s = String::new();
+            source(&x, &mut s);
+            let root = eval(&s).unwrap();
+            assert_eq!(root.obj.with(|v| v.clone()), [x], "{s}");
+        }
+    }
+
+    #[test]
+    fn floats() {
+        let cases = [
+            0.0,
+            -0.0,
+            1.0,
+            -2.5,
+            1e300,
+            1e-300,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        ];
+        for x in cases.into_iter().chain([f64::NAN]) {
+            let mut s = String::new();
+            float_source(x, &mut s);
+            let root = eval(&s).unwrap();
+            let [y] = root.float.with(|v| v.clone())[..] else {
+                panic!("{s} didn't push one float");
+            };
+            assert!(
+                x.to_bits() == y.to_bits() || (x.is_nan() && y.is_nan()),
+                "{s}"
+            );
+        }
+    }
+}
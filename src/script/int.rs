@@ -1,8 +1,211 @@
-use super::{Compiler, Dictionary, Stack};
-use num::BigInt;
+use super::{Compiler, Dictionary, Hint, Object, Stack};
+use core::{cmp::Ordering, fmt, ops};
+use num::{BigInt, ToPrimitive};
 use std::rc::Rc;
 
-pub fn define(comp: &Compiler, dict: &Dictionary, stack: &Rc<Stack<BigInt>>) {
+/// Arbitrary-precision integer.
+///
+/// Values that fit in a machine word are stored inline.
+/// They are only promoted to a [`BigInt`] on overflow.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Int(Repr);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Repr {
+    Small(i64),
+    /// Never fits in an `i64`.
+    Big(BigInt),
+}
+
+#[derive(Debug)]
+pub struct TryFromIntError(Int);
+
+impl Int {
+    pub const ZERO: Self = Self(Repr::Small(0));
+
+    fn small(&self) -> Option<i64> {
+        match &self.0 {
+            Repr::Small(x) => Some(*x),
+            Repr::Big(_) => None,
+        }
+    }
+
+    pub fn to_bigint(&self) -> BigInt {
+        match &self.0 {
+            Repr::Small(x) => (*x).into(),
+            Repr::Big(x) => x.clone(),
+        }
+    }
+
+    pub fn min(self, rhs: Self) -> Self {
+        Ord::min(self, rhs)
+    }
+
+    pub fn max(self, rhs: Self) -> Self {
+        Ord::max(self, rhs)
+    }
+
+    /// Apply an operation on machine words, falling back to [`BigInt`]s on overflow.
+    fn op<F, G>(self, rhs: Self, small: F, big: G) -> Self
+    where
+        F: FnOnce(i64, i64) -> Option<i64>,
+        G: FnOnce(BigInt, BigInt) -> BigInt,
+    {
+        if let (Some(x), Some(y)) = (self.small(), rhs.small())
+            && let Some(z) = (small)(x, y)
+        {
+            return z.into();
+        }
+        (big)(self.into(), rhs.into()).into()
+    }
+}
+
+impl Default for Int {
+    fn default() -> Self {
+        Self::ZERO
+    }
+}
+
+impl PartialOrd for Int {
+    fn partial_cmp(&self, rhs: &Self) -> Option<Ordering> {
+        Some(self.cmp(rhs))
+    }
+}
+
+impl Ord for Int {
+    fn cmp(&self, rhs: &Self) -> Ordering {
+        match (&self.0, &rhs.0) {
+            (Repr::Small(x), Repr::Small(y)) => x.cmp(y),
+            _ => self.to_bigint().cmp(&rhs.to_bigint()),
+        }
+    }
+}
+
+impl fmt::Display for Int {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Repr::Small(x) => x.fmt(f),
+            Repr::Big(x) => x.fmt(f),
+        }
+    }
+}
+
+impl From<BigInt> for Int {
+    fn from(x: BigInt) -> Self {
+        Self(x.to_i64().map_or(Repr::Big(x), Repr::Small))
+    }
+}
+
+impl From<Int> for BigInt {
+    fn from(x: Int) -> Self {
+        match x.0 {
+            Repr::Small(x) => x.into(),
+            Repr::Big(x) => x,
+        }
+    }
+}
+
+macro_rules! from_primitive {
+    ($($t:ty)*) => {
+        $(
+            impl From<$t> for Int {
+                fn from(x: $t) -> Self {
+                    i64::try_from(x).map_or_else(|_| BigInt::from(x).into(), |x| Self(Repr::Small(x)))
+                }
+            }
+
+            impl TryFrom<Int> for $t {
+                type Error = TryFromIntError;
+
+                fn try_from(x: Int) -> Result<Self, Self::Error> {
+                    match &x.0 {
+                        Repr::Small(y) => <$t>::try_from(*y).ok(),
+                        Repr::Big(y) => <$t>::try_from(y).ok(),
+                    }
+                    .ok_or(TryFromIntError(x))
+                }
+            }
+        )*
+    };
+}
+
+from_primitive!(u8 u16 u32 u64 usize i8 i16 i32 i64 isize);
+
+impl From<bool> for Int {
+    fn from(x: bool) -> Self {
+        Self(Repr::Small(x.into()))
+    }
+}
+
+impl fmt::Display for TryFromIntError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "integer {} is out of range", self.0)
+    }
+}
+
+impl std::error::Error for TryFromIntError {}
+
+macro_rules! binop {
+    ($($trait:ident $fn:ident $small:expr)*) => {
+        $(
+            impl ops::$trait for Int {
+                type Output = Self;
+
+                fn $fn(self, rhs: Self) -> Self {
+                    self.op(rhs, $small, ops::$trait::$fn)
+                }
+            }
+        )*
+    };
+}
+
+binop! {
+    Add add i64::checked_add
+    Sub sub i64::checked_sub
+    Mul mul i64::checked_mul
+    BitAnd bitand |x, y| Some(x & y)
+    BitOr bitor |x, y| Some(x | y)
+    BitXor bitxor |x, y| Some(x ^ y)
+}
+
+impl ops::Neg for Int {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        match self.small().and_then(i64::checked_neg) {
+            Some(x) => x.into(),
+            None => (-BigInt::from(self)).into(),
+        }
+    }
+}
+
+impl ops::Shl<usize> for Int {
+    type Output = Self;
+
+    fn shl(self, rhs: usize) -> Self {
+        let small = self
+            .small()
+            .filter(|_| rhs < 64)
+            .and_then(|x| i64::try_from(i128::from(x) << rhs).ok());
+        match small {
+            Some(x) => x.into(),
+            None => (BigInt::from(self) << rhs).into(),
+        }
+    }
+}
+
+impl ops::Shr<usize> for Int {
+    type Output = Self;
+
+    fn shr(self, rhs: usize) -> Self {
+        match self.small() {
+            Some(x) => (x >> rhs.min(63)).into(),
+            None => (BigInt::from(self) >> rhs).into(),
+        }
+    }
+}
+
+pub fn define(comp: &Compiler, dict: &Dictionary, stack: &Rc<Stack<Int>>, obj: &Rc<Stack<Object>>) {
     fn f<T, F>((comp, stack, dict): (&Compiler, &Rc<Stack<T>>, &Dictionary), name: &str, f: F)
     where
         F: 'static + Fn(&Stack<T>) -> super::Result<()> + 'static,
@@ -12,17 +215,43 @@ pub fn define(comp: &Compiler, dict: &Dictionary, stack: &Rc<Stack<BigInt>>) {
         let stack = stack.clone();
         dict.define(name, comp.with(move || (f)(&stack)));
     }
+    fn g(
+        (comp, stack, dict): (&Compiler, &Rc<Stack<Int>>, &Dictionary),
+        name: &str,
+        f: fn(Int, Int) -> Option<Int>,
+    ) {
+        let stack = stack.clone();
+        let name2 = Box::<str>::from(name);
+        dict.define(
+            name,
+            comp.with_hint(Hint::Int2(f), move || {
+                stack.op2to1(|x, y| {
+                    (f)(x, y).ok_or_else(|| format!("{name2}: invalid operands").into())
+                })
+            }),
+        );
+    }
+    fn h(
+        (comp, stack, dict): (&Compiler, &Rc<Stack<Int>>, &Dictionary),
+        name: &str,
+        hint: Hint,
+        f: fn(&Stack<Int>) -> super::Result<()>,
+    ) {
+        let stack = stack.clone();
+        dict.define(name, comp.with_hint(hint, move || (f)(&stack)));
+    }
     let s = (comp, stack, dict);
-    f(s, "+", |s| s.op2to1(|x, y| x + y));
-    f(s, "-", |s| s.op2to1(|x, y| x - y));
-    f(s, "*", |s| s.op2to1(|x, y| x * y));
-    f(s, "=", |s| s.op2to1(|x, y| (x == y).into()));
-    f(s, "<>", |s| s.op2to1(|x, y| (x != y).into()));
-    f(s, "<", |s| s.op2to1(|x, y| (x < y).into()));
-    f(s, ">", |s| s.op2to1(|x, y| (x > y).into()));
-    f(s, "<=", |s| s.op2to1(|x, y| (x <= y).into()));
-    f(s, ">=", |s| s.op2to1(|x, y| (x >= y).into()));
-    f(s, "#dup", |s| {
+    super::define_shuffles(comp, dict, "#", stack, stack);
+    g(s, "+", |x, y| Some(x + y));
+    g(s, "-", |x, y| Some(x - y));
+    g(s, "*", |x, y| Some(x * y));
+    g(s, "=", |x, y| Some((x == y).into()));
+    g(s, "<>", |x, y| Some((x != y).into()));
+    g(s, "<", |x, y| Some((x < y).into()));
+    g(s, ">", |x, y| Some((x > y).into()));
+    g(s, "<=", |x, y| Some((x <= y).into()));
+    g(s, ">=", |x, y| Some((x >= y).into()));
+    h(s, "#dup", Hint::IntDup, |s| {
         let x = s.pop()?;
         s.push(x.clone())?;
         s.push(x)
@@ -35,28 +264,35 @@ pub fn define(comp: &Compiler, dict: &Dictionary, stack: &Rc<Stack<BigInt>>) {
         s.push(x)?;
         s.push(y)
     });
-    f(s, "#drop", |s| s.pop().map(|_| ()));
-    f(s, "#swap", |s| {
+    h(s, "#drop", Hint::IntDrop, |s| s.pop().map(|_| ()));
+    h(s, "#swap", Hint::IntSwap, |s| {
         let x = s.pop()?;
         let y = s.pop()?;
         s.push(x)?;
         s.push(y)
     });
-    f(s, "#min", |s| s.op2to1(|x, y| x.min(y)));
-    f(s, "#max", |s| s.op2to1(|x, y| x.max(y)));
-    let to_usize = |x| usize::try_from(x).unwrap();
-    f(s, "#bit:shl", move |s| s.op2to1(|x, y| x << to_usize(y)));
-    f(s, "#bit:shr", move |s| s.op2to1(|x, y| x >> to_usize(y)));
-    f(s, "#bit:and", move |s| s.op2to1(|x, y| x & y));
-    f(s, "#bit:or", move |s| s.op2to1(|x, y| x | y));
-    f(s, "#bit:xor", move |s| s.op2to1(|x, y| x ^ y));
-    let s = stack.clone();
+    g(s, "#min", |x, y| Some(x.min(y)));
+    g(s, "#max", |x, y| Some(x.max(y)));
+    g(s, "#bit:shl", |x, y| Some(x << usize::try_from(y).ok()?));
+    g(s, "#bit:shr", |x, y| Some(x >> usize::try_from(y).ok()?));
+    g(s, "#bit:and", |x, y| Some(x & y));
+    g(s, "#bit:or", |x, y| Some(x | y));
+    g(s, "#bit:xor", |x, y| Some(x ^ y));
+    let (c, o) = (comp.clone(), obj.clone());
+    f(s, "#for", move |s| {
+        let w = c.token_word(&o.pop()?)?;
+        let end = s.pop()?;
+        let mut i = s.pop()?;
+        while i < end {
+            s.push(i.clone())?;
+            w()?;
+            i = i + Int::from(1);
+        }
+        Ok(())
+    });
     let comp = comp.clone();
     dict.push_alt(move |name| {
-        let f = |x: BigInt| {
-            let s = s.clone();
-            comp.with(move || s.push(x.clone()))
-        };
+        let f = |x: BigInt| comp.int(x.into());
         if name.len() > 2 && name.starts_with("'") && name.ends_with("'") {
             let mut it = name.chars().skip(1);
             let c = match it.next().unwrap() {
@@ -103,3 +339,32 @@ fn from_string(s: &str) -> Option<BigInt> {
         })
         .map(|n| if neg { -n } else { n })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overflow() {
+        let min = Int::from(i64::MIN);
+        let big = -BigInt::from(i64::MIN);
+        assert_eq!(BigInt::from(-min.clone()), big);
+        assert_eq!((-min.clone()).small(), None);
+        assert_eq!(BigInt::from(min.clone() * Int::from(-1)), big);
+        assert_eq!(-(-min.clone()), min);
+        assert_eq!(Int::from(i64::MAX) + Int::from(1), Int::from(big.clone()));
+        assert_eq!((Int::from(big) - Int::from(1)).small(), Some(i64::MAX));
+    }
+
+    #[test]
+    fn shifts() {
+        assert_eq!((Int::from(1) << 62).small(), Some(1 << 62));
+        assert_eq!((Int::from(-1) << 63).small(), Some(i64::MIN));
+        assert_eq!(BigInt::from(Int::from(1) << 63), BigInt::from(1) << 63);
+        assert_eq!(BigInt::from(Int::from(1) << 64), BigInt::from(1) << 64);
+        assert_eq!(BigInt::from(Int::from(-2) << 63), BigInt::from(-2) << 63);
+        assert_eq!(Int::from(i64::MIN) >> 64, Int::from(-1));
+        assert_eq!(Int::from(i64::MAX) >> 100, Int::ZERO);
+        assert_eq!((Int::from(1) << 64) >> 64, Int::from(1));
+    }
+}
@@ -0,0 +1,402 @@
+use super::{Compiler, Dictionary, Object, Stack};
+use core::fmt::Write;
+use num::BigInt;
+use std::rc::Rc;
+
+/// Maximum nesting of arrays and objects, so parsing can't overflow the stack.
+const MAX_NESTING: usize = 512;
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, msg: &str) -> super::Error {
+        let before = &self.src[..self.pos];
+        let line = before.iter().filter(|x| **x == b'\n').count() + 1;
+        let col = before.len()
+            - before
+                .iter()
+                .rposition(|x| *x == b'\n')
+                .map_or(0, |i| i + 1)
+            + 1;
+        format!("line {line}, column {col}: {msg}").into()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, s: &[u8]) -> super::Result<()> {
+        if !self.src[self.pos..].starts_with(s) {
+            return Err(self.error(&format!("expected {:?}", String::from_utf8_lossy(s))));
+        }
+        self.pos += s.len();
+        Ok(())
+    }
+
+    fn value(&mut self) -> super::Result<Object> {
+        self.skip_whitespace();
+        let tag = |s: &str, refs: Vec<Object>| Object::new(s.as_bytes().into(), refs.into());
+        if matches!(self.peek(), Some(b'[' | b'{')) && self.depth == MAX_NESTING {
+            return Err(self.error("nesting is too deep"));
+        }
+        match self.peek() {
+            Some(b'n') => self.expect(b"null").map(|_| tag("null", vec![])),
+            Some(b't') => self.expect(b"true").map(|_| tag("true", vec![])),
+            Some(b'f') => self.expect(b"false").map(|_| tag("false", vec![])),
+            Some(b'"') => Ok(tag("string", vec![self.string()?])),
+            Some(b'-' | b'0'..=b'9') => Ok(tag("number", vec![self.number()?])),
+            Some(b'[') => {
+                self.pos += 1;
+                let v = self.list(b']', |p| p.value())?;
+                Ok(tag("array", v))
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let v = self.list(b'}', |p| {
+                    p.skip_whitespace();
+                    if p.peek() != Some(b'"') {
+                        return Err(p.error("expected string key"));
+                    }
+                    let k = p.string()?;
+                    p.skip_whitespace();
+                    p.expect(b":")?;
+                    Ok([k, p.value()?].into())
+                })?;
+                Ok(tag("object", v))
+            }
+            Some(_) => Err(self.error("expected value")),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn list<F>(&mut self, end: u8, mut f: F) -> super::Result<Vec<Object>>
+    where
+        F: FnMut(&mut Self) -> super::Result<Object>,
+    {
+        let mut v = vec![];
+        self.skip_whitespace();
+        if self.peek() == Some(end) {
+            self.pos += 1;
+            return Ok(v);
+        }
+        self.depth += 1;
+        loop {
+            v.push(f(self)?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(c) if c == end => {
+                    self.pos += 1;
+                    self.depth -= 1;
+                    return Ok(v);
+                }
+                _ => return Err(self.error(&format!("expected ',' or '{}'", end as char))),
+            }
+        }
+    }
+
+    fn string(&mut self) -> super::Result<Object> {
+        self.pos += 1;
+        let mut s = vec![];
+        loop {
+            let Some(c) = self.peek() else {
+                return Err(self.error("unterminated string"));
+            };
+            match c {
+                b'"' => {
+                    self.pos += 1;
+                    return Ok(s.into());
+                }
+                b'\\' => {
+                    self.pos += 1;
+                    let c = match self.peek() {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\x08',
+                        Some(b'f') => '\x0c',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            self.pos += 1;
+                            let mut x = self.hex4()?;
+                            if (0xd800..0xdc00).contains(&x) {
+                                self.expect(b"\\u")?;
+                                let y = self.hex4()?;
+                                if !(0xdc00..0xe000).contains(&y) {
+                                    return Err(self.error("invalid low surrogate"));
+                                }
+                                x = 0x10000 + ((x - 0xd800) << 10) + (y - 0xdc00);
+                            }
+                            let c =
+                                char::from_u32(x).ok_or_else(|| self.error("invalid escape"))?;
+                            s.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                            continue;
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    self.pos += 1;
+                    s.push(c as u8);
+                }
+                0..0x20 => return Err(self.error("control character in string")),
+                c => {
+                    self.pos += 1;
+                    s.push(c);
+                }
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> super::Result<u32> {
+        let x = self
+            .src
+            .get(self.pos..self.pos + 4)
+            .and_then(|x| core::str::from_utf8(x).ok())
+            .and_then(|x| u32::from_str_radix(x, 16).ok())
+            .ok_or_else(|| self.error("expected 4 hex digits"))?;
+        self.pos += 4;
+        Ok(x)
+    }
+
+    fn number(&mut self) -> super::Result<Object> {
+        let start = self.pos;
+        let digits = |p: &mut Self| {
+            let s = p.pos;
+            while p.peek().is_some_and(|c| c.is_ascii_digit()) {
+                p.pos += 1;
+            }
+            if s == p.pos {
+                Err(p.error("expected digit"))
+            } else {
+                Ok(())
+            }
+        };
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        if self.peek() == Some(b'0') {
+            self.pos += 1;
+        } else {
+            digits(self)?;
+        }
+        let mut integer = true;
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            digits(self)?;
+            integer = false;
+        }
+        if matches!(self.peek(), Some(b'e' | b'E')) {
+            self.pos += 1;
+            if matches!(self.peek(), Some(b'+' | b'-')) {
+                self.pos += 1;
+            }
+            digits(self)?;
+            integer = false;
+        }
+        let s = core::str::from_utf8(&self.src[start..self.pos])?;
+        if integer {
+            // normalizes -0
+            Ok(s.parse::<BigInt>()?.to_string().into())
+        } else {
+            Ok(s.into())
+        }
+    }
+}
+
+/// Parse a value into an object with the kind as data and the contents as refs.
+///
+/// Numbers and strings have their text as only ref and objects have `[key value]` pairs.
+pub fn parse(src: &[u8]) -> super::Result<Object> {
+    let mut p = Parser {
+        src,
+        pos: 0,
+        depth: 0,
+    };
+    let v = p.value()?;
+    p.skip_whitespace();
+    if p.pos != src.len() {
+        return Err(p.error("trailing data after value"));
+    }
+    Ok(v)
+}
+
+/// Serialize a value, indenting with `indent` spaces per level if it is not [`None`].
+pub fn serialize(v: &Object, indent: Option<usize>) -> super::Result<Vec<u8>> {
+    fn newline(out: &mut Vec<u8>, indent: Option<usize>, depth: usize) {
+        if let Some(n) = indent {
+            out.push(b'\n');
+            out.extend(core::iter::repeat_n(b' ', n * depth));
+        }
+    }
+    fn string(out: &mut Vec<u8>, s: &[u8]) {
+        out.push(b'"');
+        for c in s {
+            match c {
+                b'"' => out.extend_from_slice(b"\\\""),
+                b'\\' => out.extend_from_slice(b"\\\\"),
+                b'\n' => out.extend_from_slice(b"\\n"),
+                b'\r' => out.extend_from_slice(b"\\r"),
+                b'\t' => out.extend_from_slice(b"\\t"),
+                0..0x20 | 0x7f => {
+                    let mut s = String::new();
+                    write!(s, "\\u{c:04x}").unwrap();
+                    out.extend_from_slice(s.as_bytes());
+                }
+                c => out.push(*c),
+            }
+        }
+        out.push(b'"');
+    }
+    fn f(out: &mut Vec<u8>, v: &Object, indent: Option<usize>, depth: usize) -> super::Result<()> {
+        let (open, close) = match (v.data(), v.refs()) {
+            (b"null" | b"true" | b"false", []) => {
+                out.extend_from_slice(v.data());
+                return Ok(());
+            }
+            (b"number", [x]) => {
+                out.extend_from_slice(x.data());
+                return Ok(());
+            }
+            (b"string", [x]) => {
+                string(out, x.data());
+                return Ok(());
+            }
+            (b"array", _) => (b'[', b']'),
+            (b"object", _) => (b'{', b'}'),
+            _ => return Err("object is not a JSON value".into()),
+        };
+        out.push(open);
+        for (i, x) in v.refs().iter().enumerate() {
+            if i > 0 {
+                out.push(b',');
+            }
+            newline(out, indent, depth + 1);
+            if open == b'[' {
+                f(out, x, indent, depth + 1)?;
+                continue;
+            }
+            let [k, x] = x.refs() else {
+                return Err("JSON object member must be a [key value] pair".into());
+            };
+            string(out, k.data());
+            out.push(b':');
+            if indent.is_some() {
+                out.push(b' ');
+            }
+            f(out, x, indent, depth + 1)?;
+        }
+        if !v.refs().is_empty() {
+            newline(out, indent, depth);
+        }
+        out.push(close);
+        Ok(())
+    }
+    let mut out = vec![];
+    f(&mut out, v, indent, 0)?;
+    Ok(out)
+}
+
+pub fn define<F>(
+    comp: &Compiler,
+    dictionary: &Dictionary,
+    read_word: &Rc<F>,
+    obj: &Rc<Stack<Object>>,
+) where
+    F: 'static + Fn() -> super::Result<Option<String>>,
+{
+    let (s1, s2, s3) = (obj.clone(), obj.clone(), obj.clone());
+    dictionary.dict(
+        "Json",
+        read_word,
+        &[
+            (
+                "parse",
+                comp.with(move || s1.push(parse(s1.pop()?.data())?)),
+            ),
+            (
+                "compact",
+                comp.with(move || s2.push(serialize(&s2.pop()?, None)?.into())),
+            ),
+            (
+                "pretty",
+                comp.with(move || s3.push(serialize(&s3.pop()?, Some(2))?.into())),
+            ),
+        ],
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(src: &str, compact: &str) {
+        let v = parse(src.as_bytes()).unwrap();
+        assert_eq!(serialize(&v, None).unwrap(), compact.as_bytes());
+        let pretty = serialize(&v, Some(2)).unwrap();
+        assert_eq!(parse(&pretty).unwrap(), v);
+    }
+
+    #[test]
+    fn values() {
+        round_trip(
+            " [1, -0, 2.5e3, true, false, null] ",
+            "[1,0,2.5e3,true,false,null]",
+        );
+        round_trip(
+            r#"{"a": {"b": [[], {}]}, "": "x"}"#,
+            r#"{"a":{"b":[[],{}]},"":"x"}"#,
+        );
+    }
+
+    #[test]
+    fn escapes() {
+        round_trip(
+            r#""q\"b\\s\/n\nr\rt\tc\u0001\u00e9\ud83d\ude00""#,
+            "\"q\\\"b\\\\s/n\\nr\\rt\\tc\\u0001\u{e9}\u{1f600}\"",
+        );
+        let v = parse(br#""line\nbreak""#).unwrap();
+        assert_eq!(v.refs()[0].data(), b"line\nbreak");
+    }
+
+    #[test]
+    fn errors() {
+        let error = |src: &str| parse(src.as_bytes()).unwrap_err().to_string();
+        assert_eq!(error(""), "line 1, column 1: unexpected end of input");
+        assert_eq!(error("[1,\n  2,\n  x]"), "line 3, column 3: expected value");
+        assert_eq!(error("{\"a\" 1}"), "line 1, column 6: expected \":\"");
+        assert_eq!(error("[1 2]"), "line 1, column 4: expected ',' or ']'");
+        assert_eq!(
+            error("\"a\nb\""),
+            "line 1, column 3: control character in string"
+        );
+        assert_eq!(
+            error("\"\\ud800x\""),
+            "line 1, column 8: expected \"\\\\u\""
+        );
+        assert_eq!(error("1 2"), "line 1, column 3: trailing data after value");
+        assert_eq!(error("01"), "line 1, column 2: trailing data after value");
+    }
+
+    #[test]
+    fn nesting() {
+        let ok = "[".repeat(MAX_NESTING) + &"]".repeat(MAX_NESTING);
+        assert!(parse(ok.as_bytes()).is_ok());
+        let deep = "[".repeat(100_000);
+        let e = parse(deep.as_bytes()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!("line 1, column {}: nesting is too deep", MAX_NESTING + 1)
+        );
+    }
+}
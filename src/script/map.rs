@@ -0,0 +1,369 @@
+use super::{Compiler, Dictionary, Int, Object, Stack, with_stacks};
+use core::cmp::Ordering;
+use std::rc::Rc;
+
+/// Size of the header of a node: height and size as `u64`s.
+const HEADER: usize = 16;
+
+struct Node<'a> {
+    l: &'a Object,
+    k: &'a Object,
+    v: &'a Object,
+    r: &'a Object,
+}
+
+/// Get the refs `[left key value right]` of a node, or [`None`] for the empty map.
+///
+/// The stored height and size of the node are checked against its children.
+fn node(m: &Object) -> super::Result<Option<Node<'_>>> {
+    let (h, n) = unchecked_header(m)?;
+    let [l, k, v, r] = m.refs() else {
+        return Ok(None);
+    };
+    let (a, b) = (unchecked_header(l)?, unchecked_header(r)?);
+    let valid = Some(h) == a.0.max(b.0).checked_add(1)
+        && Some(n) == a.1.checked_add(b.1).and_then(|x| x.checked_add(1));
+    if !valid {
+        return Err("object is not a map".into());
+    }
+    Ok(Some(Node { l, k, v, r }))
+}
+
+/// Get the height and size of a map without checking its children.
+fn unchecked_header(m: &Object) -> super::Result<(usize, usize)> {
+    match m.refs() {
+        [] if m.data().is_empty() => Ok((0, 0)),
+        [_, _, _, _] if m.data().len() == HEADER => {
+            let f = |i: usize| {
+                let x = u64::from_le_bytes(m.data()[i * 8..][..8].try_into().unwrap());
+                usize::try_from(x)
+            };
+            Ok((f(0)?, f(1)?))
+        }
+        _ => Err("object is not a map".into()),
+    }
+}
+
+fn header(m: &Object) -> super::Result<(usize, usize)> {
+    node(m)?;
+    unchecked_header(m)
+}
+
+fn height(m: &Object) -> super::Result<usize> {
+    Ok(header(m)?.0)
+}
+
+fn size(m: &Object) -> super::Result<usize> {
+    Ok(header(m)?.1)
+}
+
+fn make(l: Object, k: Object, v: Object, r: Object) -> super::Result<Object> {
+    let h = height(&l)?.max(height(&r)?).checked_add(1);
+    let n = size(&l)?
+        .checked_add(size(&r)?)
+        .and_then(|x| x.checked_add(1));
+    let (Some(h), Some(n)) = (h, n) else {
+        return Err("object is not a map".into());
+    };
+    let header = [h, n]
+        .iter()
+        .flat_map(|x| (*x as u64).to_le_bytes())
+        .collect();
+    Ok(Object::new(header, [l, k, v, r].into()))
+}
+
+/// Create a node, rotating if the heights of the subtrees differ by 2.
+fn balance(l: Object, k: Object, v: Object, r: Object) -> super::Result<Object> {
+    let (hl, hr) = (height(&l)?, height(&r)?);
+    if hl > hr + 1 {
+        let a = node(&l)?.unwrap();
+        if height(a.l)? >= height(a.r)? {
+            let r = make(a.r.clone(), k, v, r)?;
+            make(a.l.clone(), a.k.clone(), a.v.clone(), r)
+        } else {
+            let b = node(a.r)?.unwrap();
+            let l = make(a.l.clone(), a.k.clone(), a.v.clone(), b.l.clone())?;
+            let r = make(b.r.clone(), k, v, r)?;
+            make(l, b.k.clone(), b.v.clone(), r)
+        }
+    } else if hr > hl + 1 {
+        let a = node(&r)?.unwrap();
+        if height(a.r)? >= height(a.l)? {
+            let l = make(l, k, v, a.l.clone())?;
+            make(l, a.k.clone(), a.v.clone(), a.r.clone())
+        } else {
+            let b = node(a.l)?.unwrap();
+            let l = make(l, k, v, b.l.clone())?;
+            let r = make(b.r.clone(), a.k.clone(), a.v.clone(), a.r.clone())?;
+            make(l, b.k.clone(), b.v.clone(), r)
+        }
+    } else {
+        make(l, k, v, r)
+    }
+}
+
+fn insert(m: &Object, k: Object, v: Object) -> super::Result<Object> {
+    let Some(n) = node(m)? else {
+        return make(Object::default(), k, v, Object::default());
+    };
+    let (l, r) = (n.l.clone(), n.r.clone());
+    match k.cmp(n.k) {
+        Ordering::Less => balance(insert(&l, k, v)?, n.k.clone(), n.v.clone(), r),
+        Ordering::Greater => balance(l, n.k.clone(), n.v.clone(), insert(&r, k, v)?),
+        Ordering::Equal => make(l, k, v, r),
+    }
+}
+
+/// Remove the smallest entry, returning it and the remaining map.
+fn remove_min(m: &Object) -> super::Result<(Object, Object, Object)> {
+    let n = node(m)?.ok_or("map is empty")?;
+    if node(n.l)?.is_none() {
+        return Ok((n.k.clone(), n.v.clone(), n.r.clone()));
+    }
+    let (k, v, l) = remove_min(n.l)?;
+    let m = balance(l, n.k.clone(), n.v.clone(), n.r.clone())?;
+    Ok((k, v, m))
+}
+
+fn remove(m: &Object, k: &Object) -> super::Result<Object> {
+    let Some(n) = node(m)? else {
+        return Ok(m.clone());
+    };
+    let (l, r) = (n.l.clone(), n.r.clone());
+    match k.cmp(n.k) {
+        Ordering::Less => balance(remove(&l, k)?, n.k.clone(), n.v.clone(), r),
+        Ordering::Greater => balance(l, n.k.clone(), n.v.clone(), remove(&r, k)?),
+        Ordering::Equal if node(&r)?.is_none() => Ok(l),
+        Ordering::Equal => {
+            let (k, v, r) = remove_min(&r)?;
+            balance(l, k, v, r)
+        }
+    }
+}
+
+fn get(m: &Object, k: &Object) -> super::Result<Option<Object>> {
+    let mut m = m;
+    while let Some(n) = node(m)? {
+        m = match k.cmp(n.k) {
+            Ordering::Less => n.l,
+            Ordering::Greater => n.r,
+            Ordering::Equal => return Ok(Some(n.v.clone())),
+        };
+    }
+    Ok(None)
+}
+
+/// Get the `i`th entry in key order.
+fn nth(m: &Object, mut i: usize) -> super::Result<Option<(Object, Object)>> {
+    let mut m = m;
+    while let Some(n) = node(m)? {
+        let s = size(n.l)?;
+        m = match i.cmp(&s) {
+            Ordering::Less => n.l,
+            Ordering::Greater => {
+                // i > s, so this can't overflow
+                i -= s + 1;
+                n.r
+            }
+            Ordering::Equal => return Ok(Some((n.k.clone(), n.v.clone()))),
+        };
+    }
+    Ok(None)
+}
+
+fn each(m: &Object, f: &mut dyn FnMut(&Object, &Object)) -> super::Result<()> {
+    if let Some(n) = node(m)? {
+        each(n.l, f)?;
+        (f)(n.k, n.v);
+        each(n.r, f)?;
+    }
+    Ok(())
+}
+
+pub fn define<F>(
+    comp: &Compiler,
+    dictionary: &Dictionary,
+    read_word: &Rc<F>,
+    int: &Rc<Stack<Int>>,
+    obj: &Rc<Stack<Object>>,
+) where
+    F: 'static + Fn() -> super::Result<Option<String>>,
+{
+    dictionary.dict(
+        "Map",
+        read_word,
+        &[
+            (
+                "new",
+                with_stacks(comp, int, obj, |_, obj| obj.push(Object::default())),
+            ),
+            (
+                "insert",
+                with_stacks(comp, int, obj, |_, obj| {
+                    let v = obj.pop()?;
+                    let k = obj.pop()?;
+                    let m = obj.pop()?;
+                    obj.push(insert(&m, k, v)?)
+                }),
+            ),
+            (
+                "get",
+                with_stacks(comp, int, obj, |int, obj| {
+                    let k = obj.pop()?;
+                    let v = get(&obj.pop()?, &k)?;
+                    int.push(v.is_some().into())?;
+                    obj.push(v.unwrap_or_default())
+                }),
+            ),
+            (
+                "remove",
+                with_stacks(comp, int, obj, |_, obj| {
+                    let k = obj.pop()?;
+                    let m = obj.pop()?;
+                    obj.push(remove(&m, &k)?)
+                }),
+            ),
+            (
+                "keys",
+                with_stacks(comp, int, obj, |_, obj| {
+                    let mut v = vec![];
+                    each(&obj.pop()?, &mut |k, _| v.push(k.clone()))?;
+                    obj.push(v.into_iter().collect())
+                }),
+            ),
+            (
+                "length",
+                with_stacks(comp, int, obj, |int, obj| {
+                    int.push(size(&obj.pop()?)?.into())
+                }),
+            ),
+            (
+                "nth",
+                with_stacks(comp, int, obj, |int, obj| {
+                    let i = usize::try_from(int.pop()?)?;
+                    let (k, v) = nth(&obj.pop()?, i)?
+                        .ok_or_else(|| format!("entry {i} is out of bounds"))?;
+                    obj.push(k)?;
+                    obj.push(v)
+                }),
+            ),
+            (
+                "pairs",
+                with_stacks(comp, int, obj, |_, obj| {
+                    let mut v = vec![];
+                    each(&obj.pop()?, &mut |k, x| {
+                        v.push(Object::from([k.clone(), x.clone()]))
+                    })?;
+                    obj.push(v.into_iter().collect())
+                }),
+            ),
+            (
+                "from-pairs",
+                with_stacks(comp, int, obj, |_, obj| {
+                    let mut m = Object::default();
+                    for p in obj.pop()?.refs() {
+                        let [k, v] = p.refs() else {
+                            return Err("pair must have exactly two refs".into());
+                        };
+                        m = insert(&m, k.clone(), v.clone())?;
+                    }
+                    obj.push(m)
+                }),
+            ),
+        ],
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check the invariants of a map and return its height.
+    fn check(m: &Object) -> usize {
+        let Some(n) = node(m).unwrap() else {
+            return 0;
+        };
+        let (hl, hr) = (check(n.l), check(n.r));
+        assert!(hl.abs_diff(hr) <= 1, "unbalanced");
+        assert_eq!(height(m).unwrap(), hl.max(hr) + 1);
+        assert_eq!(
+            size(m).unwrap(),
+            size(n.l).unwrap() + size(n.r).unwrap() + 1
+        );
+        for (x, ord) in [(n.l, Ordering::Less), (n.r, Ordering::Greater)] {
+            each(x, &mut |k, _| assert_eq!(k.cmp(n.k), ord)).unwrap();
+        }
+        hl.max(hr) + 1
+    }
+
+    fn key(i: usize) -> Object {
+        format!("{i:05}").into()
+    }
+
+    #[test]
+    fn balance() {
+        let mut m = Object::default();
+        for i in 0..1000 {
+            m = insert(&m, key(i * 7919 % 1000), key(i)).unwrap();
+            check(&m);
+        }
+        assert_eq!(size(&m).unwrap(), 1000);
+        // an AVL tree with 1000 entries is at most 14 high
+        assert!(check(&m) <= 14);
+        for i in (0..1000).step_by(3) {
+            m = remove(&m, &key(i)).unwrap();
+            check(&m);
+        }
+        for i in 0..1000 {
+            let v = get(&m, &key(i)).unwrap();
+            assert_eq!(v.is_none(), i % 3 == 0);
+        }
+        let keys = (0..1000)
+            .filter(|i| i % 3 != 0)
+            .map(key)
+            .collect::<Vec<_>>();
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(&nth(&m, i).unwrap().unwrap().0, k);
+        }
+        assert!(nth(&m, keys.len()).unwrap().is_none());
+        for k in &keys {
+            m = remove(&m, k).unwrap();
+            check(&m);
+        }
+        assert_eq!(m, Object::default());
+    }
+
+    #[test]
+    fn replace() {
+        let m = insert(&Object::default(), key(1), key(2)).unwrap();
+        let m = insert(&m, key(1), key(3)).unwrap();
+        assert_eq!(size(&m).unwrap(), 1);
+        assert_eq!(get(&m, &key(1)).unwrap(), Some(key(3)));
+    }
+
+    #[test]
+    fn invalid() {
+        let forge = |h: u64, n: u64, l: Object, r: Object| {
+            let header = [h, n].iter().flat_map(|x| x.to_le_bytes()).collect();
+            Object::new(header, [l, key(0), key(0), r].into())
+        };
+        let leaf = forge(1, 1, Object::default(), Object::default());
+        assert!(node(&leaf).is_ok());
+        // a leaf with data is not the empty map
+        assert!(insert(&Object::from("abc"), key(1), key(2)).is_err());
+        assert!(get(&Object::from("abc"), &key(1)).is_err());
+        // headers that don't match the children
+        for (h, n) in [(2, 1), (1, 2), (0, 1), (1, 0), (u64::MAX, 1), (1, u64::MAX)] {
+            let m = forge(h, n, Object::default(), Object::default());
+            assert!(node(&m).is_err());
+            assert!(insert(&m, key(1), key(2)).is_err());
+            assert!(nth(&m, 5).is_err());
+        }
+        // children whose own headers overflow their parent
+        let big = forge(u64::MAX, u64::MAX, leaf.clone(), leaf.clone());
+        let m = forge(u64::MAX, u64::MAX, big.clone(), Object::default());
+        assert!(node(&m).is_err());
+        let m = forge(1, 1, big, leaf);
+        assert!(node(&m).is_err());
+    }
+}
@@ -1,25 +1,53 @@
+pub mod aot;
+mod array;
+mod bytes;
 mod compiler;
+mod csv;
+mod encode;
+mod float;
+mod image;
+mod inspect;
 mod int;
+mod json;
+mod map;
 mod object;
+mod record;
 mod string;
 mod sys;
+mod text;
 mod var;
 
-use compiler::Compiler;
-use num::BigInt;
-use object::Object;
+use compiler::{Compiler, Hint, Op};
+use image::Sym;
+pub use int::Int;
+pub use object::Object;
 use std::{cell::Cell, collections::BTreeMap, rc::Rc};
 use with_cell::WithCell;
 
-type Error = Box<dyn std::error::Error>;
-type Result<T> = core::result::Result<T, Error>;
+pub type Error = Box<dyn std::error::Error>;
+pub type Result<T> = core::result::Result<T, Error>;
 
-type Word = Rc<dyn Fn() -> Result<()>>;
+type AltWord = Box<dyn Fn(&str) -> Option<Word>>;
+type Streams = Rc<WithCell<Vec<Box<dyn FnMut() -> Option<u8>>>>>;
+
+#[derive(Clone)]
+struct Word(Rc<WordData>);
+
+struct WordData {
+    f: Box<dyn Fn() -> Result<()>>,
+    /// Operations to insert instead of the word while compiling.
+    ops: Option<Rc<[Op]>>,
+    /// Words that can follow this word if it is a vocabulary.
+    vocab: Option<Rc<BTreeMap<Box<str>, Word>>>,
+    sym: WithCell<Sym>,
+}
 
 #[derive(Default)]
 struct DictionaryData {
     words: BTreeMap<Box<str>, Word>,
-    alt: Option<Box<dyn Fn(&str) -> Option<Word>>>,
+    /// Words defined by the host, by their full path.
+    natives: BTreeMap<Rc<str>, Word>,
+    alt: Option<AltWord>,
 }
 
 #[derive(Clone)]
@@ -29,11 +57,71 @@ struct Stack<T> {
     stack: Cell<Vec<T>>,
 }
 
+impl Word {
+    fn new<F>(f: F, ops: Option<Rc<[Op]>>, vocab: Option<Rc<BTreeMap<Box<str>, Word>>>) -> Self
+    where
+        F: 'static + Fn() -> Result<()>,
+    {
+        Self(Rc::new(WordData {
+            f: Box::new(f),
+            ops,
+            vocab,
+            sym: Default::default(),
+        }))
+    }
+
+    fn sym(&self) -> Sym {
+        self.0.sym.with(|s| s.clone())
+    }
+
+    /// Give the word and the word it compiles to a symbol, unless it already has one.
+    ///
+    /// Returns `false` if the word already had a symbol.
+    fn set_sym(&self, sym: Sym) -> bool {
+        if !matches!(self.sym(), Sym::None) {
+            return false;
+        }
+        if let Some([Op::Call(w, _)]) = self.0.ops.as_deref() {
+            w.set_sym(sym.clone());
+        }
+        self.0.sym.set(sym);
+        true
+    }
+
+    fn ptr_eq(&self, rhs: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &rhs.0)
+    }
+}
+
+impl core::ops::Deref for Word {
+    type Target = dyn Fn() -> Result<()>;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0.f
+    }
+}
+
 impl Dictionary {
     fn define(&self, word: &str, value: Word) {
+        self.name(word.into(), &value);
         self.0.with(|d| d.words.insert(word.into(), value));
     }
 
+    /// Name a word and its vocabulary, if it hasn't been named already.
+    fn name(&self, path: Rc<str>, word: &Word) {
+        if !word.set_sym(Sym::Native(path.clone())) {
+            return;
+        }
+        for (k, v) in word.0.vocab.iter().flat_map(|x| x.iter()) {
+            self.name(format!("{path} {k}").into(), v);
+        }
+        self.0.with(|d| d.natives.insert(path, word.clone()));
+    }
+
+    fn native(&self, path: &str) -> Option<Word> {
+        self.0.with(|d| d.natives.get(path).cloned())
+    }
+
     fn dict<F>(&self, word: &str, read_word: &Rc<F>, values: &[(&str, Word)])
     where
         F: 'static + Fn() -> Result<Option<String>>,
@@ -94,13 +182,30 @@ impl<T> Stack<T> {
         })?)
     }
 
+    /// Rearrange the stack, but only if it has at least `n` values.
+    fn shuffle<F>(&self, name: &str, n: usize, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut Vec<T>, usize),
+    {
+        self.with(|v| {
+            let len = v.len();
+            if len < n {
+                return Err(
+                    format!("{name}: stack underflow, needs {n} values but has {len}").into(),
+                );
+            }
+            (f)(v, len);
+            Ok(())
+        })
+    }
+
     fn op2to1<F>(&self, f: F) -> Result<()>
     where
-        F: FnOnce(T, T) -> T,
+        F: FnOnce(T, T) -> Result<T>,
     {
         let y = self.pop()?;
         let x = self.pop()?;
-        self.push((f)(x, y))
+        self.push((f)(x, y)?)
     }
 }
 
@@ -112,54 +217,180 @@ impl<T> Default for Stack<T> {
     }
 }
 
+/// Define the shuffle words that both stacks have, with the given prefix.
+///
+/// Counts and depths are on the integer stack.
+fn define_shuffles<T>(
+    comp: &Compiler,
+    dict: &Dictionary,
+    prefix: &str,
+    stack: &Rc<Stack<T>>,
+    int: &Rc<Stack<Int>>,
+) where
+    T: 'static + Clone,
+{
+    let f = |name: &str, n: usize, f: fn(&mut Vec<T>, usize)| {
+        let (s, name) = (stack.clone(), format!("{prefix}{name}"));
+        dict.define(&name.clone(), comp.with(move || s.shuffle(&name, n, f)));
+    };
+    f("over", 2, |v, l| v.push(v[l - 2].clone()));
+    f("rot", 3, |v, l| v[l - 3..].rotate_left(1));
+    f("-rot", 3, |v, l| v[l - 3..].rotate_right(1));
+    f("nip", 2, |v, l| drop(v.remove(l - 2)));
+    f("tuck", 2, |v, l| v.insert(l - 2, v[l - 1].clone()));
+    f("2drop", 2, |v, l| v.truncate(l - 2));
+    f("2swap", 4, |v, l| v[l - 4..].rotate_left(2));
+    let g = |name: &str, f: fn(&mut Vec<T>, usize, usize)| {
+        let (s, int, name) = (stack.clone(), int.clone(), format!("{prefix}{name}"));
+        dict.define(
+            &name.clone(),
+            comp.with(move || {
+                let x = int.pop()?;
+                let r = usize::try_from(x.clone())
+                    .map_err(Into::into)
+                    .and_then(|u| {
+                        let n = u.checked_add(1).ok_or("count is too large")?;
+                        s.shuffle(&name, n, |v, l| (f)(v, l, u))
+                    });
+                // leave the stacks as they were
+                if r.is_err() {
+                    int.push(x)?;
+                }
+                r
+            }),
+        );
+    };
+    g("pick", |v, l, u| v.push(v[l - 1 - u].clone()));
+    g("roll", |v, l, u| v[l - 1 - u..].rotate_left(1));
+    let (s, int) = (stack.clone(), int.clone());
+    dict.define(
+        &format!("{prefix}depth"),
+        comp.with(move || {
+            let n = s.with(|v| v.len());
+            int.push(n.into())
+        }),
+    );
+}
+
 /// Create VM with all capabilities.
 pub fn create_root_vm<A>(args: A) -> impl FnMut(&[u8]) -> Result<()>
 where
     A: IntoIterator<Item = String>,
 {
-    let streams = Rc::<WithCell<Vec<Box<dyn FnMut() -> Option<u8>>>>>::default();
-    let dictionary = Dictionary(Default::default());
-
-    let s = streams.clone();
-    let read_word = Rc::new(move || -> Result<Option<String>> {
-        let mut word = vec![];
-        let mut quote = None;
-        while let Some(x) = s.with(|s| s.last_mut().and_then(|x| (x)())) {
-            if quote.is_none() && x.is_ascii_whitespace() {
-                if word.is_empty() {
-                    continue;
-                }
-                break;
-            } else if b"\"'`".contains(&x) {
-                quote = quote.is_none().then_some(x);
-            }
-            word.push(x);
+    root_vm(args, None).unwrap()
+}
+
+/// Create VM with all capabilities from an image saved with `Sys Vm save-image`.
+///
+/// Execution resumes right after the word that saved the image.
+pub fn load_root_vm<A>(image: &[u8], args: A) -> Result<impl FnMut(&[u8]) -> Result<()> + use<A>>
+where
+    A: IntoIterator<Item = String>,
+{
+    root_vm(args, Some(image))
+}
+
+fn root_vm<A>(args: A, image: Option<&[u8]>) -> Result<impl FnMut(&[u8]) -> Result<()> + use<A>>
+where
+    A: IntoIterator<Item = String>,
+{
+    let root = Root::new();
+    if let Some(image) = image {
+        let source = image::load(
+            image,
+            &root.comp,
+            &root.dictionary,
+            &root.int,
+            &root.obj,
+            &root.float,
+        )?;
+        root.push_source(source);
+    }
+    Ok(root.run(args))
+}
+
+/// The parts of a VM with all capabilities.
+struct Root<F> {
+    streams: Streams,
+    dictionary: Dictionary,
+    read_word: Rc<F>,
+    comp: Compiler,
+    int: Rc<Stack<Int>>,
+    obj: Rc<Stack<Object>>,
+    float: Rc<Stack<f64>>,
+}
+
+impl Root<()> {
+    fn new() -> Root<impl Fn() -> Result<Option<String>>> {
+        let streams = Streams::default();
+        let dictionary = Dictionary(Default::default());
+
+        let s = streams.clone();
+        let read_word = Rc::new(move || next_word(|| s.with(|s| s.last_mut().and_then(|x| (x)()))));
+
+        let int = Rc::new(Stack::<Int>::default());
+        let obj = Rc::new(Stack::<Object>::default());
+        let float = Rc::new(Stack::<f64>::default());
+
+        let comp = compiler::define(read_word.clone(), &dictionary, &int, &obj, &float);
+        int::define(&comp, &dictionary, &int, &obj);
+        object::define(&comp, &dictionary, &int, &obj);
+        float::define(&comp, &dictionary, &int, &obj, &float);
+        encode::define(&comp, &dictionary, &obj);
+        inspect::define(&comp, &dictionary, &int, &obj, &float);
+        sys::define(&comp, &dictionary, &read_word, &streams, &int, &obj, &float);
+        string::define(&comp, &dictionary, &read_word, &int, &obj);
+        text::define(&comp, &dictionary, &read_word, &int, &obj);
+        map::define(&comp, &dictionary, &read_word, &int, &obj);
+        json::define(&comp, &dictionary, &read_word, &obj);
+        csv::define(&comp, &dictionary, &read_word, &int, &obj);
+        bytes::define(&comp, &dictionary, &read_word, &int, &obj);
+        var::define(&comp, &read_word, &dictionary, &int, &obj);
+        array::define(&comp, &read_word, &dictionary, &int, &obj);
+        record::define(&comp, &read_word, &dictionary, &int, &obj);
+
+        Root {
+            streams,
+            dictionary,
+            read_word,
+            comp,
+            int,
+            obj,
+            float,
         }
-        (!word.is_empty())
-            .then(|| Ok(String::from_utf8(word)?))
-            .transpose()
-    });
-
-    let def_int = Rc::new(Stack::<BigInt>::default());
-    let def_obj = Rc::new(Stack::<Object>::default());
-
-    let comp = &compiler::define(read_word.clone(), &dictionary, &def_int, &def_obj);
-    int::define(comp, &dictionary, &def_int);
-    object::define(comp, &dictionary, &def_int, &def_obj);
-    args.into_iter()
-        .for_each(|x| def_obj.push(x.into()).unwrap());
-    sys::define(comp, &dictionary, &read_word, &def_int, &def_obj);
-    string::define(comp, &dictionary, &read_word, &def_int, &def_obj);
-    var::define(comp, &read_word, &dictionary, &def_int, &def_obj);
-
-    move |s| {
-        if !s.is_empty() {
-            let mut s = Vec::from(s).into_iter();
-            streams.with(|x| x.push(Box::new(move || s.next())));
-            return Ok(());
+    }
+}
+
+impl<F> Root<F>
+where
+    F: Fn() -> Result<Option<String>>,
+{
+    fn push_source(&self, source: Vec<u8>) {
+        let mut s = source.into_iter();
+        self.streams.with(|x| x.push(Box::new(move || s.next())));
+    }
+
+    /// Push the arguments and start interpreting.
+    fn run<A>(self, args: A) -> impl FnMut(&[u8]) -> Result<()>
+    where
+        A: IntoIterator<Item = String>,
+    {
+        args.into_iter()
+            .for_each(|x| self.obj.push(x.into()).unwrap());
+        move |s: &[u8]| {
+            if !s.is_empty() {
+                self.push_source(s.into());
+                return Ok(());
+            }
+            self.interpret()
         }
-        while let Some(x) = read_word()? {
-            let x = dictionary
+    }
+
+    /// Run words until the sources are exhausted.
+    fn interpret(&self) -> Result<()> {
+        while let Some(x) = (self.read_word)()? {
+            let x = self
+                .dictionary
                 .get(&x)
                 .ok_or_else(|| format!("undefined word {x:?}"))?;
             (x)()?;
@@ -168,12 +399,53 @@ where
     }
 }
 
+/// Run a script in a fresh VM so tests can inspect its stacks.
+#[cfg(test)]
+fn eval(source: &str) -> Result<Root<impl Fn() -> Result<Option<String>>>> {
+    let root = Root::new();
+    root.push_source(source.into());
+    root.interpret()?;
+    Ok(root)
+}
+
+/// Read a single word, which is separated by whitespace unless quoted.
+fn next_word<F>(mut next: F) -> Result<Option<String>>
+where
+    F: FnMut() -> Option<u8>,
+{
+    let mut word = vec![];
+    let mut quote = None;
+    while let Some(x) = (next)() {
+        if quote.is_none() && x.is_ascii_whitespace() {
+            if word.is_empty() {
+                continue;
+            }
+            break;
+        } else if b"\"'`".contains(&x) {
+            quote = quote.is_none().then_some(x);
+        }
+        word.push(x);
+    }
+    (!word.is_empty())
+        .then(|| Ok(String::from_utf8(word)?))
+        .transpose()
+}
+
+/// Create a word from a closure that takes both stacks.
+fn with_stacks<G>(comp: &Compiler, int: &Rc<Stack<Int>>, obj: &Rc<Stack<Object>>, g: G) -> Word
+where
+    G: 'static + Fn(&Stack<Int>, &Stack<Object>) -> Result<()>,
+{
+    let (int, obj) = (int.clone(), obj.clone());
+    comp.with(move || (g)(&int, &obj))
+}
+
 /// Create an immediate word from a closure.
 fn with_imm<F>(f: F) -> Word
 where
     F: 'static + Fn() -> Result<()>,
 {
-    Rc::new(f)
+    Word::new(f, None, None)
 }
 
 fn dict<F>(read_word: Rc<F>, words: &[(&str, Word)]) -> Word
@@ -184,11 +456,99 @@ where
         .iter()
         .map(|(k, v)| (Box::from(*k), v.clone()))
         .collect::<BTreeMap<_, _>>();
-    with_imm(move || {
+    let words = Rc::new(words);
+    let w = words.clone();
+    let f = move || {
         let word = read_word()?.unwrap();
-        let x = words
+        let x = w
             .get(&*word)
             .ok_or_else(|| format!("{word:?} is undefined"))?;
         (x)()
-    })
+    };
+    Word::new(f, None, Some(words))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shuffle_underflow() {
+        let cases = [
+            (
+                "1 2 #rot",
+                "#rot: stack underflow, needs 3 values but has 2",
+            ),
+            (
+                "1 2 #-rot",
+                "#-rot: stack underflow, needs 3 values but has 2",
+            ),
+            (
+                "1 #over",
+                "#over: stack underflow, needs 2 values but has 1",
+            ),
+            ("#nip", "#nip: stack underflow, needs 2 values but has 0"),
+            (
+                "1 #tuck",
+                "#tuck: stack underflow, needs 2 values but has 1",
+            ),
+            (
+                "1 #2drop",
+                "#2drop: stack underflow, needs 2 values but has 1",
+            ),
+            (
+                "1 2 3 #2swap",
+                "#2swap: stack underflow, needs 4 values but has 3",
+            ),
+            (
+                "1 2 2 #pick",
+                "#pick: stack underflow, needs 3 values but has 2",
+            ),
+            (
+                "1 2 5 #roll",
+                "#roll: stack underflow, needs 6 values but has 2",
+            ),
+            (
+                "\"a\" \"b\" \"c\" @2swap",
+                "@2swap: stack underflow, needs 4 values but has 3",
+            ),
+            (
+                "\"a\" \"b\" @rot",
+                "@rot: stack underflow, needs 3 values but has 2",
+            ),
+            (
+                "\"a\" 1 @pick",
+                "@pick: stack underflow, needs 2 values but has 1",
+            ),
+            (
+                "1.5 %over",
+                "%over: stack underflow, needs 2 values but has 1",
+            ),
+            (
+                "1.5 2.5 0 %roll 3 %roll",
+                "%roll: stack underflow, needs 4 values but has 2",
+            ),
+        ];
+        for (source, message) in cases {
+            let root = Root::new();
+            root.push_source(source.into());
+            let e = root.interpret().unwrap_err();
+            assert_eq!(e.to_string(), message);
+            // the words before the shuffle leave what they pushed
+            let words = source.rsplit_once(' ').map_or("", |x| x.0);
+            let expected = eval(words).unwrap();
+            assert_eq!(
+                root.int.with(|v| v.clone()),
+                expected.int.with(|v| v.clone())
+            );
+            assert_eq!(
+                root.obj.with(|v| v.clone()),
+                expected.obj.with(|v| v.clone())
+            );
+            assert_eq!(
+                root.float.with(|v| v.clone()),
+                expected.float.with(|v| v.clone())
+            );
+        }
+    }
 }
@@ -1,43 +1,230 @@
-use super::{BigInt, Compiler, Dictionary, Stack};
-use core::ops::Range;
+use super::{Compiler, Dictionary, Hint, Int, Stack, Word};
+use core::{cmp::Ordering, ops::Range};
+use memchr::memmem;
 use std::rc::Rc;
 
+/// Maximum nesting of objects read from untrusted data, so reading them can't overflow the stack.
+pub const MAX_DEPTH: usize = 1024;
+
+/// Immutable tree of bytes and references.
+///
+/// Storage is shared, so clones and slices are cheap.
 #[derive(Clone, Debug, Default)]
 pub struct Object {
-    data: Box<[u8]>,
-    refs: Box<[Object]>,
+    data: Shared<u8>,
+    refs: Shared<Object>,
+}
+
+/// Identity of the storage of an object, see [`Object::storage_id`].
+pub type StorageId = (*const u8, Range<usize>, *const Object, Range<usize>);
+
+/// A range of reference-counted storage.
+#[derive(Clone)]
+struct Shared<T> {
+    buf: Rc<[T]>,
+    range: Range<usize>,
+}
+
+impl<T> Shared<T> {
+    fn get(&self) -> &[T] {
+        &self.buf[self.range.clone()]
+    }
+
+    fn slice(&self, range: Range<usize>) -> Self {
+        // bounds check
+        let _ = &self.get()[range.clone()];
+        let start = self.range.start;
+        Self {
+            buf: self.buf.clone(),
+            range: start + range.start..start + range.end,
+        }
+    }
+
+    fn concat(&self, rhs: &Self) -> Self
+    where
+        T: Clone,
+    {
+        if rhs.range.is_empty() {
+            self.clone()
+        } else if self.range.is_empty() {
+            rhs.clone()
+        } else {
+            self.get().iter().chain(rhs.get()).cloned().collect()
+        }
+    }
+
+    /// Replace a range with the contents of another.
+    fn splice(&self, range: Range<usize>, with: &Self) -> Self
+    where
+        T: Clone,
+    {
+        let x = self.get();
+        if range.is_empty() && with.range.is_empty() {
+            return self.clone();
+        }
+        x[..range.start]
+            .iter()
+            .chain(with.get())
+            .chain(&x[range.end..])
+            .cloned()
+            .collect()
+    }
+}
+
+impl<T> Default for Shared<T> {
+    fn default() -> Self {
+        Box::<[T]>::default().into()
+    }
+}
+
+impl<T> From<Box<[T]>> for Shared<T> {
+    fn from(buf: Box<[T]>) -> Self {
+        Self {
+            range: 0..buf.len(),
+            buf: buf.into(),
+        }
+    }
+}
+
+impl<T> FromIterator<T> for Shared<T> {
+    fn from_iter<I>(iter: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+    {
+        iter.into_iter().collect::<Box<[T]>>().into()
+    }
+}
+
+impl<T: core::fmt::Debug> core::fmt::Debug for Shared<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.get().fmt(f)
+    }
 }
 
 impl Object {
+    pub fn new(data: Box<[u8]>, refs: Box<[Object]>) -> Self {
+        Self {
+            data: data.into(),
+            refs: refs.into(),
+        }
+    }
+
     pub fn data(&self) -> &[u8] {
-        &self.data
+        self.data.get()
     }
 
     pub fn refs(&self) -> &[Object] {
-        &self.refs
+        self.refs.get()
+    }
+
+    /// Identify the storage of an object.
+    ///
+    /// Objects with the same identity are equal while both are alive.
+    pub fn storage_id(&self) -> StorageId {
+        (
+            self.data.buf.as_ptr(),
+            self.data.range.clone(),
+            self.refs.buf.as_ptr(),
+            self.refs.range.clone(),
+        )
     }
 
     pub fn concat(&self, rhs: &Self) -> Self {
         Self {
-            data: self.data().iter().chain(rhs.data()).cloned().collect(),
-            refs: self.refs().iter().chain(rhs.refs()).cloned().collect(),
+            data: self.data.concat(&rhs.data),
+            refs: self.refs.concat(&rhs.refs),
         }
     }
 
     pub fn slice(&self, data: Range<usize>, refs: Range<usize>) -> Self {
         Self {
-            data: self.data()[data].iter().cloned().collect(),
-            refs: self.refs()[refs].iter().cloned().collect(),
+            data: self.data.slice(data),
+            refs: self.refs.slice(refs),
         }
     }
+
+    /// Replace the given ranges with the bytes and refs of `with`.
+    pub fn splice(&self, data: Range<usize>, refs: Range<usize>, with: &Self) -> Self {
+        Self {
+            data: self.data.splice(data, &with.data),
+            refs: self.refs.splice(refs, &with.refs),
+        }
+    }
+
+    pub fn reverse(&self) -> Self {
+        Self {
+            data: self.data().iter().rev().copied().collect(),
+            refs: self.refs().iter().rev().cloned().collect(),
+        }
+    }
+}
+
+impl PartialEq for Object {
+    fn eq(&self, rhs: &Self) -> bool {
+        self.data() == rhs.data() && self.refs() == rhs.refs()
+    }
+}
+
+impl Eq for Object {}
+
+/// Bytes first, then refs, both lexicographically.
+impl Ord for Object {
+    fn cmp(&self, rhs: &Self) -> core::cmp::Ordering {
+        self.data()
+            .cmp(rhs.data())
+            .then_with(|| self.refs().cmp(rhs.refs()))
+    }
+}
+
+impl PartialOrd for Object {
+    fn partial_cmp(&self, rhs: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(rhs))
+    }
+}
+
+impl core::hash::Hash for Object {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        self.data().hash(state);
+        self.refs().hash(state);
+    }
+}
+
+/// 64-bit FNV-1a, which unlike [`std::hash::DefaultHasher`] is the same across builds.
+struct Fnv(u64);
+
+impl Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 = (self.0 ^ u64::from(*b)).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn write_len(&mut self, n: usize) {
+        self.write(&(n as u64).to_le_bytes())
+    }
+}
+
+impl Object {
+    /// Hash of the contents that is stable across runs and platforms.
+    ///
+    /// Each node is fed as a tag byte, the data and the refs, both after their length.
+    pub fn stable_hash(&self) -> u64 {
+        fn f(x: &Object, h: &mut Fnv) {
+            h.write(&[0]);
+            h.write_len(x.data().len());
+            h.write(x.data());
+            h.write_len(x.refs().len());
+            x.refs().iter().for_each(|x| f(x, h));
+        }
+        let mut h = Fnv(0xcbf29ce484222325);
+        f(self, &mut h);
+        h.0
+    }
 }
 
 impl From<Box<[u8]>> for Object {
     fn from(data: Box<[u8]>) -> Self {
-        Self {
-            data,
-            refs: [].into(),
-        }
+        Self::new(data, [].into())
     }
 }
 
@@ -49,10 +236,7 @@ impl<const N: usize> From<[u8; N]> for Object {
 
 impl<const N: usize> From<[Object; N]> for Object {
     fn from(refs: [Object; N]) -> Self {
-        Self {
-            data: [].into(),
-            refs: refs.into(),
-        }
+        Self::new([].into(), refs.into())
     }
 }
 
@@ -80,7 +264,7 @@ impl FromIterator<Self> for Object {
         I: IntoIterator<Item = Self>,
     {
         Self {
-            data: [].into(),
+            data: Default::default(),
             refs: iter.into_iter().collect(),
         }
     }
@@ -90,16 +274,96 @@ impl<'a> TryFrom<&'a Object> for &'a str {
     type Error = core::str::Utf8Error;
 
     fn try_from(obj: &'a Object) -> core::result::Result<Self, Self::Error> {
-        core::str::from_utf8(&obj.data)
+        core::str::from_utf8(obj.data())
+    }
+}
+
+/// Check an index against a length.
+fn index(i: Int, len: usize, what: &str) -> super::Result<usize> {
+    usize::try_from(i.clone())
+        .ok()
+        .filter(|i| *i < len)
+        .ok_or_else(|| format!("{what} {i} is out of bounds").into())
+}
+
+/// Compare two objects with a word that pushes a negative, zero or positive integer.
+fn compare(
+    w: &Word,
+    (int, obj): (&Stack<Int>, &Stack<Object>),
+    x: &Object,
+    y: &Object,
+) -> super::Result<Ordering> {
+    obj.push(x.clone())?;
+    obj.push(y.clone())?;
+    w()?;
+    Ok(int.pop()?.cmp(&Int::ZERO))
+}
+
+/// Stably sort refs with a fallible comparison, stopping at the first error.
+///
+/// This is a merge sort, so unlike [`slice::sort_by`] an inconsistent comparison can't panic.
+fn sort_by<F>(x: &Object, mut f: F) -> super::Result<Object>
+where
+    F: FnMut(&Object, &Object) -> super::Result<Ordering>,
+{
+    let mut refs = x.refs().to_vec();
+    let mut buf = Vec::with_capacity(refs.len());
+    let mut width = 1;
+    while width < refs.len() {
+        buf.clear();
+        for chunk in refs.chunks(2 * width) {
+            let (mut a, mut b) = chunk.split_at(width.min(chunk.len()));
+            while let ([x, xs @ ..], [y, ys @ ..]) = (a, b) {
+                // equal refs are taken from the left to keep their order
+                if f(x, y)? == Ordering::Greater {
+                    buf.push(y.clone());
+                    b = ys;
+                } else {
+                    buf.push(x.clone());
+                    a = xs;
+                }
+            }
+            buf.extend_from_slice(a);
+            buf.extend_from_slice(b);
+        }
+        core::mem::swap(&mut refs, &mut buf);
+        width *= 2;
+    }
+    Ok(Object::new(x.data().into(), refs.into()))
+}
+
+/// Find a key in sorted refs, returning where it is or should be inserted.
+fn search_by<F>(x: &Object, mut f: F) -> super::Result<(usize, bool)>
+where
+    F: FnMut(&Object) -> super::Result<Ordering>,
+{
+    let (mut lo, mut hi) = (0, x.refs().len());
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        match f(&x.refs()[mid])? {
+            Ordering::Less => lo = mid + 1,
+            Ordering::Greater => hi = mid,
+            Ordering::Equal => return Ok((mid, true)),
+        }
     }
+    Ok((lo, false))
 }
 
-pub fn define(
-    comp: &Compiler,
-    dict: &Dictionary,
-    int: &Rc<Stack<BigInt>>,
-    obj: &Rc<Stack<Object>>,
-) {
+fn decimal(x: &Object) -> super::Result<Int> {
+    let s = <&str>::try_from(x)?;
+    let x = s
+        .parse::<num::BigInt>()
+        .map_err(|_| format!("{s:?} is not a decimal number"))?;
+    Ok(x.into())
+}
+
+/// Push an index and a found flag, where a missing index is -1.
+fn push_found(int: &Stack<Int>, i: Option<usize>) -> super::Result<()> {
+    int.push(i.map_or(Int::from(-1), Int::from))?;
+    int.push(i.is_some().into())
+}
+
+pub fn define(comp: &Compiler, dict: &Dictionary, int: &Rc<Stack<Int>>, obj: &Rc<Stack<Object>>) {
     fn f<T, F>((comp, stack): (&Compiler, &Rc<Stack<T>>), dict: &Dictionary, name: &str, f: F)
     where
         F: 'static + Fn(&Stack<T>) -> super::Result<()> + 'static,
@@ -109,14 +373,25 @@ pub fn define(
         let stack = stack.clone();
         dict.define(name, comp.with(move || (f)(&stack)));
     }
+    fn h(
+        (comp, stack): (&Compiler, &Rc<Stack<Object>>),
+        dict: &Dictionary,
+        name: &str,
+        hint: Hint,
+        f: fn(&Stack<Object>) -> super::Result<()>,
+    ) {
+        let stack = stack.clone();
+        dict.define(name, comp.with_hint(hint, move || (f)(&stack)));
+    }
     let s = (comp, obj);
-    f(s, dict, "@dup", |s| {
+    super::define_shuffles(comp, dict, "@", obj, int);
+    h(s, dict, "@dup", Hint::ObjDup, |s| {
         let x = s.pop()?;
         s.push(x.clone())?;
         s.push(x)
     });
-    f(s, dict, "@drop", |s| s.pop().map(|_| ()));
-    f(s, dict, "@swap", |s| {
+    h(s, dict, "@drop", Hint::ObjDrop, |s| s.pop().map(|_| ()));
+    h(s, dict, "@swap", Hint::ObjSwap, |s| {
         let x = s.pop()?;
         let y = s.pop()?;
         s.push(x)?;
@@ -126,7 +401,7 @@ pub fn define(
     f(s, dict, "@byte", move |s| {
         let i = int2.pop()?;
         let i = usize::try_from(i).unwrap();
-        let x = *s.pop()?.data.get(i).unwrap();
+        let x = *s.pop()?.data().get(i).unwrap();
         int2.push(x.into())
     });
     let int2 = int.clone();
@@ -135,11 +410,11 @@ pub fn define(
         let i = usize::try_from(i).unwrap();
         let x = s
             .pop()?
-            .refs
+            .refs()
             .get(i)
             .ok_or_else(|| format!("ref {i} is out of bounds"))?
             .clone();
-        s.push(x.into())
+        s.push(x)
     });
     let int2 = int.clone();
     f(s, dict, "@refcount", move |s| {
@@ -167,4 +442,410 @@ pub fn define(
     f(s, dict, "@intobyte", move |s| {
         s.push(Object::from([u8::try_from(int2.pop()?)?]))
     });
+    let int2 = int.clone();
+    f(s, dict, "@insertref", move |s| {
+        let x = s.pop()?;
+        let y = s.pop()?;
+        let i = index(int2.pop()?, y.refs().len() + 1, "ref")?;
+        s.push(y.splice(0..0, i..i, &[x].into()))
+    });
+    let int2 = int.clone();
+    f(s, dict, "@removeref", move |s| {
+        let y = s.pop()?;
+        let i = index(int2.pop()?, y.refs().len(), "ref")?;
+        s.push(y.splice(0..0, i..i + 1, &Object::default()))
+    });
+    let int2 = int.clone();
+    f(s, dict, "@setref", move |s| {
+        let x = s.pop()?;
+        let y = s.pop()?;
+        let i = index(int2.pop()?, y.refs().len(), "ref")?;
+        s.push(y.splice(0..0, i..i + 1, &[x].into()))
+    });
+    let int2 = int.clone();
+    f(s, dict, "@insertbyte", move |s| {
+        let x = u8::try_from(int2.pop()?)?;
+        let y = s.pop()?;
+        let i = index(int2.pop()?, y.data().len() + 1, "byte")?;
+        s.push(y.splice(i..i, 0..0, &[x].into()))
+    });
+    let int2 = int.clone();
+    f(s, dict, "@removebyte", move |s| {
+        let y = s.pop()?;
+        let i = index(int2.pop()?, y.data().len(), "byte")?;
+        s.push(y.splice(i..i + 1, 0..0, &Object::default()))
+    });
+    let int2 = int.clone();
+    f(s, dict, "@setbyte", move |s| {
+        let x = u8::try_from(int2.pop()?)?;
+        let y = s.pop()?;
+        let i = index(int2.pop()?, y.data().len(), "byte")?;
+        s.push(y.splice(i..i + 1, 0..0, &[x].into()))
+    });
+    let int2 = int.clone();
+    f(s, dict, "@=", move |s| {
+        int2.push((s.pop()? == s.pop()?).into())
+    });
+    let int2 = int.clone();
+    f(s, dict, "@compare", move |s| {
+        let y = s.pop()?;
+        let x = s.pop()?;
+        int2.push((x.cmp(&y) as i8).into())
+    });
+    let int2 = int.clone();
+    f(s, dict, "@hash", move |s| {
+        int2.push(s.pop()?.stable_hash().into())
+    });
+    f(s, dict, "@reverse", move |s| s.push(s.pop()?.reverse()));
+    let int2 = int.clone();
+    f(s, dict, "@splitat", move |s| {
+        let y = s.pop()?;
+        let refs = index(int2.pop()?, y.refs().len() + 1, "ref")?;
+        let data = index(int2.pop()?, y.data().len() + 1, "byte")?;
+        s.push(y.slice(0..data, 0..refs))?;
+        s.push(y.slice(data..y.data().len(), refs..y.refs().len()))
+    });
+    let c = comp.clone();
+    f(s, dict, "@map", move |s| {
+        let w = c.token_word(&s.pop()?)?;
+        let x = s.pop()?;
+        let refs = x
+            .refs()
+            .iter()
+            .map(|x| {
+                s.push(x.clone())?;
+                w()?;
+                s.pop()
+            })
+            .collect::<super::Result<_>>()?;
+        s.push(Object::new(x.data().into(), refs))
+    });
+    let c = comp.clone();
+    f(s, dict, "@each", move |s| {
+        let w = c.token_word(&s.pop()?)?;
+        s.pop()?.refs().iter().try_for_each(|x| {
+            s.push(x.clone())?;
+            w()
+        })
+    });
+    let (c, int2) = (comp.clone(), int.clone());
+    f(s, dict, "@filter", move |s| {
+        let w = c.token_word(&s.pop()?)?;
+        let x = s.pop()?;
+        let mut refs = vec![];
+        for x in x.refs() {
+            s.push(x.clone())?;
+            w()?;
+            if int2.pop()? != Int::ZERO {
+                refs.push(x.clone());
+            }
+        }
+        s.push(Object::new(x.data().into(), refs.into()))
+    });
+    let c = comp.clone();
+    f(s, dict, "@fold", move |s| {
+        let w = c.token_word(&s.pop()?)?;
+        let acc = s.pop()?;
+        let x = s.pop()?;
+        s.push(acc)?;
+        x.refs().iter().try_for_each(|x| {
+            s.push(x.clone())?;
+            w()
+        })
+    });
+    for (name, any) in [("@any", true), ("@all", false)] {
+        let (c, int2) = (comp.clone(), int.clone());
+        f(s, dict, name, move |s| {
+            let w = c.token_word(&s.pop()?)?;
+            for x in s.pop()?.refs() {
+                s.push(x.clone())?;
+                w()?;
+                if (int2.pop()? != Int::ZERO) == any {
+                    return int2.push(any.into());
+                }
+            }
+            int2.push((!any).into())
+        });
+    }
+    f(s, dict, "@sort", move |s| {
+        s.push(sort_by(&s.pop()?, |a, b| Ok(a.data().cmp(b.data())))?)
+    });
+    f(s, dict, "@sortnum", move |s| {
+        s.push(sort_by(
+            &s.pop()?,
+            |a, b| Ok(decimal(a)?.cmp(&decimal(b)?)),
+        )?)
+    });
+    let (c, int2) = (comp.clone(), int.clone());
+    f(s, dict, "@sortby", move |s| {
+        let w = c.token_word(&s.pop()?)?;
+        s.push(sort_by(&s.pop()?, |a, b| compare(&w, (&int2, s), a, b))?)
+    });
+    let int2 = int.clone();
+    f(s, dict, "@search", move |s| {
+        let key = s.pop()?;
+        let (i, found) = search_by(&s.pop()?, |x| Ok(x.data().cmp(key.data())))?;
+        int2.push(i.into())?;
+        int2.push(found.into())
+    });
+    let (c, int2) = (comp.clone(), int.clone());
+    f(s, dict, "@searchby", move |s| {
+        let w = c.token_word(&s.pop()?)?;
+        let key = s.pop()?;
+        let (i, found) = search_by(&s.pop()?, |x| compare(&w, (&int2, s), x, &key))?;
+        int2.push(i.into())?;
+        int2.push(found.into())
+    });
+    let int2 = int.clone();
+    f(s, dict, "@find", move |s| {
+        let needle = s.pop()?;
+        push_found(&int2, memmem::find(s.pop()?.data(), needle.data()))
+    });
+    let int2 = int.clone();
+    f(s, dict, "@rfind", move |s| {
+        let needle = s.pop()?;
+        push_found(&int2, memmem::rfind(s.pop()?.data(), needle.data()))
+    });
+    let int2 = int.clone();
+    f(s, dict, "@count", move |s| {
+        let needle = s.pop()?;
+        let x = s.pop()?;
+        // find_iter doesn't return overlapping matches
+        let n = memmem::find_iter(x.data(), needle.data()).count();
+        int2.push(n.into())
+    });
+    let int2 = int.clone();
+    f(s, dict, "@startswith", move |s| {
+        let y = s.pop()?;
+        int2.push(s.pop()?.data().starts_with(y.data()).into())
+    });
+    let int2 = int.clone();
+    f(s, dict, "@endswith", move |s| {
+        let y = s.pop()?;
+        int2.push(s.pop()?.data().ends_with(y.data()).into())
+    });
+    let int2 = int.clone();
+    f(s, dict, "@findref", move |s| {
+        let needle = s.pop()?;
+        let i = s.pop()?.refs().iter().position(|x| *x == needle);
+        push_found(&int2, i)
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(x: &Object) -> Vec<&str> {
+        x.refs()
+            .iter()
+            .map(|x| <&str>::try_from(x).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn higher_order() {
+        let root = super::super::eval(
+            r#"
+            : twice @dup @concat ;
+            : long? @bytecount 1 > ;
+            : xs "a" @intoref "bb" @intoref @concat "ccc" @intoref @concat ;
+            : doubled xs & twice @map ;
+            : twice @drop "redefined" ;
+            doubled xs & long? @filter xs "" & @concat @fold
+            xs & long? @any xs & long? @all
+            "#,
+        )
+        .unwrap();
+        let v = root.obj.with(|v| v.clone());
+        assert_eq!(strings(&v[0]), ["aa", "bbbb", "cccccc"]);
+        assert_eq!(strings(&v[1]), ["bb", "ccc"]);
+        assert_eq!(<&str>::try_from(&v[2]).unwrap(), "abbccc");
+        assert_eq!(root.int.with(|v| v.clone()), [1.into(), 0.into()]);
+    }
+
+    #[test]
+    fn tokens() {
+        let e = super::super::eval(": f & nope @map ;").err().unwrap();
+        assert_eq!(e.to_string(), "undefined word \"nope\"");
+        let e = super::super::eval(r#""" "@dup" @map"#).err().unwrap();
+        assert_eq!(e.to_string(), "object is not an execution token");
+    }
+
+    #[test]
+    fn sort() {
+        let x = (0..100)
+            .map(|i| Object::from([(i * 37 % 10) as u8, i as u8]))
+            .collect::<Object>();
+        let sorted = sort_by(&x, |a, b| Ok(a.data()[0].cmp(&b.data()[0]))).unwrap();
+        let mut expected = x.refs().to_vec();
+        expected.sort_by_key(|a| a.data()[0]);
+        assert_eq!(sorted.refs(), expected);
+        // inconsistent comparisons don't panic
+        let mut n = 0u32;
+        let y = sort_by(&x, |_, _| {
+            n = n.wrapping_mul(1103515245).wrapping_add(12345);
+            Ok([Ordering::Less, Ordering::Equal, Ordering::Greater][(n >> 16) as usize % 3])
+        })
+        .unwrap();
+        assert_eq!(y.refs().len(), x.refs().len());
+        // the first error stops the sort
+        let mut calls = 0;
+        let e = sort_by(&x, |_, _| {
+            calls += 1;
+            if calls == 10 {
+                Err("stop".into())
+            } else {
+                Ok(Ordering::Less)
+            }
+        })
+        .unwrap_err();
+        assert_eq!(e.to_string(), "stop");
+        assert_eq!(calls, 10);
+    }
+
+    fn ints(source: &str) -> Vec<Int> {
+        super::super::eval(source).unwrap().int.with(|v| v.clone())
+    }
+
+    #[test]
+    fn search() {
+        let found = |i: i64| [i.into(), (i >= 0).into()];
+        let s = r#""abcabc" "bc""#;
+        assert_eq!(ints(&format!("{s} @find")), found(1));
+        assert_eq!(ints(&format!("{s} @rfind")), found(4));
+        assert_eq!(ints(r#""abc" "x" @find"#), found(-1));
+        assert_eq!(ints(r#""abc" "x" @rfind"#), found(-1));
+        assert_eq!(ints(r#""abc" "abcd" @find"#), found(-1));
+        assert_eq!(ints(r#""abc" "abcd" @rfind"#), found(-1));
+        // the empty needle is found at both ends
+        assert_eq!(ints(r#""abc" "" @find"#), found(0));
+        assert_eq!(ints(r#""abc" "" @rfind"#), found(3));
+        assert_eq!(ints(r#""" "" @find"#), found(0));
+        // overlapping matches count once
+        assert_eq!(ints(r#""aaaa" "aa" @count"#), [2.into()]);
+        assert_eq!(ints(r#""abc" "abcd" @count"#), [0.into()]);
+        assert_eq!(ints(r#""ab" "" @count"#), [3.into()]);
+        let prefixes = r#""abc" "ab" @startswith "abc" "bc" @startswith "abc" "" @startswith"#;
+        assert_eq!(ints(prefixes), [1, 0, 1].map(Int::from));
+        let suffixes = r#""abc" "abcd" @endswith "abc" "bc" @endswith "abc" "" @endswith"#;
+        assert_eq!(ints(suffixes), [0, 1, 1].map(Int::from));
+        assert_eq!(ints(r#""ab" "abc" @startswith"#), [0.into()]);
+    }
+
+    #[test]
+    fn find_ref() {
+        let found = |i: i64| [i.into(), (i >= 0).into()];
+        let xs = r#""ab" @intoref "a" @intoref @concat "a" @intoref @intoref @concat"#;
+        // refs are compared by structure, not by bytes or contents
+        assert_eq!(ints(&format!(r#"{xs} "a" @findref"#)), found(1));
+        assert_eq!(ints(&format!(r#"{xs} "b" @findref"#)), found(-1));
+        assert_eq!(ints(&format!(r#"{xs} "a" @intoref @findref"#)), found(2));
+        assert_eq!(ints(&format!(r#"{xs} "" @findref"#)), found(-1));
+        assert_eq!(ints(r#""abc" "" @findref"#), found(-1));
+    }
+
+    fn objects(source: &str) -> Vec<Object> {
+        super::super::eval(source).unwrap().obj.with(|v| v.clone())
+    }
+
+    fn error(source: &str) -> String {
+        super::super::eval(source).err().unwrap().to_string()
+    }
+
+    #[test]
+    fn splice_bounds() {
+        let xs = r#""a" @intoref "b" @intoref @concat"#;
+        let refs = |source: &str| {
+            let v = objects(&format!("{xs} {source}"));
+            v.iter().map(|x| strings(x).join("")).collect::<Vec<_>>()
+        };
+        // inserting at the end is allowed, one past the end isn't
+        assert_eq!(refs(r#"2 "c" @insertref"#), ["abc"]);
+        assert_eq!(refs(r#"0 "c" @insertref"#), ["cab"]);
+        assert_eq!(
+            error(&format!(r#"{xs} 3 "c" @insertref"#)),
+            "ref 3 is out of bounds"
+        );
+        assert_eq!(
+            error(&format!(r#"{xs} -1 "c" @insertref"#)),
+            "ref -1 is out of bounds"
+        );
+        assert_eq!(refs("1 @removeref"), ["a"]);
+        assert_eq!(
+            error(&format!("{xs} 2 @removeref")),
+            "ref 2 is out of bounds"
+        );
+        assert_eq!(refs(r#"1 "c" @setref"#), ["ac"]);
+        assert_eq!(
+            error(&format!(r#"{xs} 2 "c" @setref"#)),
+            "ref 2 is out of bounds"
+        );
+        let bytes = |source: &str| {
+            let v = objects(&format!(r#""ab" {source}"#));
+            v.iter()
+                .map(|x| <&str>::try_from(x).unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(bytes("2 99 @insertbyte"), ["abc"]);
+        assert_eq!(error(r#""ab" 3 99 @insertbyte"#), "byte 3 is out of bounds");
+        assert_eq!(bytes("1 @removebyte"), ["a"]);
+        assert_eq!(error(r#""ab" 2 @removebyte"#), "byte 2 is out of bounds");
+        assert_eq!(bytes("1 99 @setbyte"), ["ac"]);
+        assert_eq!(error(r#""ab" 2 99 @setbyte"#), "byte 2 is out of bounds");
+        assert_eq!(error(r#""" 0 @removebyte"#), "byte 0 is out of bounds");
+        // splits at either end leave an empty half
+        assert_eq!(bytes("2 0 @splitat"), ["ab", ""]);
+        assert_eq!(bytes("0 0 @splitat"), ["", "ab"]);
+        assert_eq!(error(r#""ab" 3 0 @splitat"#), "byte 3 is out of bounds");
+        assert_eq!(error(r#""ab" 0 1 @splitat"#), "ref 1 is out of bounds");
+        assert_eq!(refs("0 2 @splitat"), ["ab", ""]);
+        assert_eq!(
+            error(&format!("{xs} 0 3 @splitat")),
+            "ref 3 is out of bounds"
+        );
+    }
+
+    #[test]
+    fn splice_shared() {
+        // slices and splits share the storage of the original object
+        let v = objects(
+            r#""abcd" "x" @intoref @concat "y" @intoref @concat
+            @dup 1 3 0 1 @slice
+            @dup 1 @removebyte
+            @over 0 "z" @setref
+            3 @pick 3 1 @splitat 0 @removebyte @swap 1 "w" @insertref"#,
+        );
+        let x = |data: &str, refs: &[&str]| {
+            let refs = refs.iter().map(|x| Object::from(*x)).collect();
+            Object::new(data.as_bytes().into(), refs)
+        };
+        assert_eq!(v[0], x("abcd", &["x", "y"]));
+        assert_eq!(v[1], x("bc", &["x"]));
+        assert_eq!(v[2], x("b", &["x"]));
+        assert_eq!(v[3], x("bc", &["z"]));
+        assert_eq!(v[4], x("", &["y"]));
+        assert_eq!(v[5], x("abc", &["x", "w"]));
+    }
+
+    #[test]
+    fn stable_hash() {
+        let x = |data: &str, refs: Vec<Object>| Object::new(data.as_bytes().into(), refs.into());
+        let all = [
+            x("", vec![]),
+            x("ab", vec![]),
+            x("a", vec![x("b", vec![])]),
+            x("", vec![x("a", vec![]), x("b", vec![])]),
+            x("", vec![x("ab", vec![])]),
+            x("", vec![x("", vec![x("ab", vec![])])]),
+        ];
+        for (i, a) in all.iter().enumerate() {
+            for b in &all[i + 1..] {
+                assert_ne!(a.stable_hash(), b.stable_hash());
+            }
+        }
+        assert_eq!(all[2].stable_hash(), all[2].clone().stable_hash());
+        // must not change between builds
+        assert_eq!(all[0].stable_hash(), 5618888146721150879);
+    }
 }
@@ -0,0 +1,150 @@
+use super::{Compiler, Dictionary, Int, Object, Stack, image::Reader, with_imm};
+use std::rc::Rc;
+
+/// First byte of the data of a record, which is never valid UTF-8 so no string is a record.
+const TAG: u8 = 0xff;
+
+pub fn define<F>(
+    comp: &Compiler,
+    read_word: &Rc<F>,
+    d: &Dictionary,
+    int: &Rc<Stack<Int>>,
+    obj: &Rc<Stack<Object>>,
+) where
+    F: 'static + Fn() -> super::Result<Option<String>>,
+{
+    let (c, d2, i, o) = (comp.clone(), d.clone(), int.clone(), obj.clone());
+    comp.loader("Struct", move |name, r: &mut Reader| {
+        let fields = (0..r.usize()?)
+            .map(|_| r.str().map(Box::from))
+            .collect::<super::Result<Vec<_>>>()?;
+        create(&c, &d2, (&i, &o), name, &fields);
+        Ok(())
+    });
+    let (c, d2, i, o, read_word) = (
+        comp.clone(),
+        d.clone(),
+        int.clone(),
+        obj.clone(),
+        read_word.clone(),
+    );
+    d.define(
+        "Struct",
+        with_imm(move || {
+            let name = read_word()?.ok_or("expected struct name")?;
+            let mut fields = vec![];
+            loop {
+                match read_word()?.as_deref() {
+                    Some("end") => break,
+                    Some(x) => fields.push(Box::from(x)),
+                    None => return Err(format!("struct {name} is never finished").into()),
+                }
+            }
+            create(&c, &d2, (&i, &o), &name, &fields);
+            Ok(())
+        }),
+    );
+}
+
+/// Define the constructor, predicate and field accessors of a struct.
+///
+/// A record has [`TAG`] and the name of its struct as data and the fields as refs.
+fn create(
+    comp: &Compiler,
+    d: &Dictionary,
+    (int, obj): (&Rc<Stack<Int>>, &Rc<Stack<Object>>),
+    name: &str,
+    fields: &[Box<str>],
+) {
+    let tag = core::iter::once(TAG)
+        .chain(name.bytes())
+        .collect::<Rc<[u8]>>();
+    let n = fields.len();
+    let is = {
+        let tag = tag.clone();
+        move |x: &Object| *x.data() == *tag && x.refs().len() == n
+    };
+    let check = {
+        let (is, name) = (is.clone(), Box::<str>::from(name));
+        move |x: &Object| {
+            if is(x) {
+                Ok(())
+            } else {
+                let got = match x.data().split_first() {
+                    Some((&TAG, got)) if *got == *name.as_bytes() => {
+                        format!("{name} with {} fields", x.refs().len())
+                    }
+                    Some((&TAG, got)) => String::from_utf8_lossy(got).into(),
+                    _ => format!("{:?}", String::from_utf8_lossy(x.data())),
+                };
+                Err::<_, super::Error>(format!("expected {name} but got {got}").into())
+            }
+        }
+    };
+    let mut words = vec![];
+    let o = obj.clone();
+    words.push(comp.with(move || {
+        let mut refs = (0..n).map(|_| o.pop()).collect::<super::Result<Vec<_>>>()?;
+        refs.reverse();
+        o.push(Object::new(tag.iter().copied().collect(), refs.into()))
+    }));
+    d.define(name, words[0].clone());
+    let (i, o) = (int.clone(), obj.clone());
+    words.push(comp.with(move || i.push(is(&o.pop()?).into())));
+    d.define(&format!("{name}?"), words[1].clone());
+    for (k, field) in fields.iter().enumerate() {
+        let (o, c) = (obj.clone(), check.clone());
+        let get = comp.with(move || {
+            let x = o.pop()?;
+            c(&x)?;
+            o.push(x.refs()[k].clone())
+        });
+        let (o, c) = (obj.clone(), check.clone());
+        let set = comp.with(move || {
+            let v = o.pop()?;
+            let x = o.pop()?;
+            c(&x)?;
+            o.push(x.splice(0..0, k..k + 1, &[v].into()))
+        });
+        d.define(&format!("{name}-{field}"), get.clone());
+        d.define(&format!("set:{name}-{field}"), set.clone());
+        words.extend([get, set]);
+    }
+    let fields = fields.to_vec();
+    comp.log("Struct", name, &words, move |w| {
+        w.usize(fields.len());
+        fields.iter().for_each(|x| w.str(x));
+        Ok(())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Int, eval};
+
+    #[test]
+    fn tags() {
+        let root = eval(
+            r#"
+            Struct Foo end
+            Struct Bar a end
+            Foo Foo? "Foo" Foo? 255 @intobyte "Foo" @concat Foo?
+            "x" Bar Bar? Foo Bar?
+            "#,
+        )
+        .unwrap();
+        let flags = root.int.with(|v| v.clone());
+        assert_eq!(flags, [1, 0, 1, 1, 0].map(Int::from));
+        let e = eval("Struct Bar a end Struct Foo end Foo Bar-a")
+            .err()
+            .unwrap();
+        assert_eq!(e.to_string(), "expected Bar but got Foo");
+        let e = eval(r#"Struct Bar a end "x" Bar "y" @intoref @concat Bar-a"#);
+        assert_eq!(
+            e.err().unwrap().to_string(),
+            "expected Bar but got Bar with 2 fields"
+        );
+        let e = eval("Struct Bar a end \"Bar\" Bar-a").err().unwrap();
+        assert_eq!(e.to_string(), "expected Bar but got \"Bar\"");
+    }
+}
@@ -1,11 +1,11 @@
-use super::{BigInt, Compiler, Dictionary, Object, Stack};
+use super::{Compiler, Dictionary, Int, Object, Stack};
 use std::rc::Rc;
 
 pub fn define<F>(
     comp: &Compiler,
     dictionary: &Dictionary,
     read_word: &Rc<F>,
-    int: &Rc<Stack<BigInt>>,
+    int: &Rc<Stack<Int>>,
     obj: &Rc<Stack<Object>>,
 ) where
     F: 'static + Fn() -> super::Result<Option<String>>,
@@ -14,7 +14,6 @@ pub fn define<F>(
     let obj = obj.clone();
     let int2 = int.clone();
     let obj2 = obj.clone();
-    let obj3 = obj.clone();
     let int4 = int.clone();
     let obj4 = obj.clone();
     dictionary.dict(
@@ -64,9 +63,7 @@ pub fn define<F>(
                 };
                 s.push(c)
             }
-            let x = Object::from(s);
-            let obj3 = obj3.clone();
-            comp.with(move || obj3.push(x.clone()))
+            comp.obj(s.into())
         })
     });
 }
@@ -1,4 +1,4 @@
-use super::{BigInt, Compiler, Dictionary, Object, Stack, Word, dict};
+use super::{Compiler, Dictionary, Int, Object, Stack, Streams, Word, dict, image};
 use crossterm::{
     cursor,
     event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
@@ -6,7 +6,7 @@ use crossterm::{
 };
 use std::rc::Rc;
 
-const KEY_ARROW_UP: i32 = (0b11 << 19) | 0b00;
+const KEY_ARROW_UP: i32 = 0b11 << 19;
 const KEY_ARROW_DOWN: i32 = (0b11 << 19) | 0b01;
 const KEY_ARROW_LEFT: i32 = (0b11 << 19) | 0b10;
 const KEY_ARROW_RIGHT: i32 = (0b11 << 19) | 0b11;
@@ -17,8 +17,10 @@ pub fn define<F>(
     comp: &Compiler,
     dictionary: &Dictionary,
     read_word: &Rc<F>,
-    int: &Rc<Stack<BigInt>>,
+    streams: &Streams,
+    int: &Rc<Stack<Int>>,
     obj: &Rc<Stack<Object>>,
+    float: &Rc<Stack<f64>>,
 ) where
     F: 'static + Fn() -> super::Result<Option<String>>,
 {
@@ -31,6 +33,10 @@ pub fn define<F>(
         read_word,
         &[
             ("Terminal", define_terminal(comp, read_word, &int, &obj)),
+            (
+                "Vm",
+                define_vm(comp, dictionary, read_word, streams, &int, &obj, float),
+            ),
             (
                 "Fs",
                 dict(
@@ -72,7 +78,7 @@ pub fn define<F>(
 fn define_terminal<F>(
     comp: &Compiler,
     read_word: &Rc<F>,
-    int: &Rc<Stack<BigInt>>,
+    int: &Rc<Stack<Int>>,
     obj: &Rc<Stack<Object>>,
 ) -> Word
 where
@@ -141,7 +147,49 @@ where
     )
 }
 
-fn encode_event(int: &Stack<BigInt>, event: Event) -> super::Result<()> {
+fn define_vm<F>(
+    comp: &Compiler,
+    dictionary: &Dictionary,
+    read_word: &Rc<F>,
+    streams: &Streams,
+    int: &Rc<Stack<Int>>,
+    obj: &Rc<Stack<Object>>,
+    float: &Rc<Stack<f64>>,
+) -> Word
+where
+    F: 'static + Fn() -> super::Result<Option<String>>,
+{
+    let (int, obj, float) = (int.clone(), obj.clone(), float.clone());
+    let int2 = int.clone();
+    let c = comp.clone();
+    let c2 = comp.clone();
+    let d = dictionary.clone();
+    let streams = streams.clone();
+    dict(
+        read_word.clone(),
+        &[
+            (
+                "optimize",
+                comp.with(move || {
+                    c.set_optimize(int.pop()? != Int::ZERO);
+                    Ok(())
+                }),
+            ),
+            (
+                "save-image",
+                comp.with(move || {
+                    let file = obj.pop()?;
+                    let file = <&str>::try_from(&file)?;
+                    let source = image::rest_of_source(&streams);
+                    let image = image::save(&c2, &d, &int2, &obj, &float, &source)?;
+                    Ok(std::fs::write(file, image)?)
+                }),
+            ),
+        ],
+    )
+}
+
+fn encode_event(int: &Stack<Int>, event: Event) -> super::Result<()> {
     match event {
         Event::FocusGained => todo!(),
         Event::FocusLost => todo!(),
@@ -0,0 +1,398 @@
+use super::{Compiler, Dictionary, Int, Object, Stack, with_stacks};
+use std::rc::Rc;
+
+/// Maximum amount of bytes in a single leaf.
+const LEAF_MAX: usize = 1024;
+/// Size of the header of a node: length, newline count and height as `u64`s.
+const HEADER: usize = 24;
+
+/// Metrics of a (sub)text.
+#[derive(Clone, Copy)]
+struct Info {
+    len: usize,
+    newlines: usize,
+    height: usize,
+}
+
+/// Get the metrics of a leaf, which has no refs, or of a node, which has two.
+///
+/// The header of a node is checked against the headers of its children.
+fn info(t: &Object) -> super::Result<Info> {
+    let x = unchecked_info(t)?;
+    let [l, r] = t.refs() else {
+        return Ok(x);
+    };
+    let (a, b) = (unchecked_info(l)?, unchecked_info(r)?);
+    let valid = Some(x.len) == a.len.checked_add(b.len)
+        && Some(x.newlines) == a.newlines.checked_add(b.newlines)
+        && Some(x.height) == a.height.max(b.height).checked_add(1);
+    if !valid {
+        return Err("object is not a text".into());
+    }
+    Ok(x)
+}
+
+fn unchecked_info(t: &Object) -> super::Result<Info> {
+    match t.refs() {
+        [] => Ok(Info {
+            len: t.data().len(),
+            newlines: t.data().iter().filter(|x| **x == b'\n').count(),
+            height: 0,
+        }),
+        [_, _] if t.data().len() == HEADER => {
+            let f = |i: usize| {
+                let x = u64::from_le_bytes(t.data()[i * 8..][..8].try_into().unwrap());
+                usize::try_from(x)
+            };
+            Ok(Info {
+                len: f(0)?,
+                newlines: f(1)?,
+                height: f(2)?,
+            })
+        }
+        _ => Err("object is not a text".into()),
+    }
+}
+
+/// Get the children of a node, or [`None`] for a leaf.
+fn children(t: &Object) -> super::Result<Option<(&Object, &Object)>> {
+    info(t)?;
+    match t.refs() {
+        [l, r] => Ok(Some((l, r))),
+        _ => Ok(None),
+    }
+}
+
+fn node(l: Object, r: Object) -> super::Result<Object> {
+    let (a, b) = (info(&l)?, info(&r)?);
+    let header = [
+        a.len + b.len,
+        a.newlines + b.newlines,
+        a.height.max(b.height) + 1,
+    ]
+    .iter()
+    .flat_map(|x| (*x as u64).to_le_bytes())
+    .collect();
+    Ok(Object::new(header, [l, r].into()))
+}
+
+/// Create a node, rotating if the heights of the children differ by 2.
+fn balance(l: Object, r: Object) -> super::Result<Object> {
+    let (hl, hr) = (info(&l)?.height, info(&r)?.height);
+    if hl > hr + 1 {
+        let (ll, lr) = children(&l)?.unwrap();
+        if info(ll)?.height >= info(lr)?.height {
+            node(ll.clone(), node(lr.clone(), r)?)
+        } else {
+            let (lrl, lrr) = children(lr)?.unwrap();
+            node(node(ll.clone(), lrl.clone())?, node(lrr.clone(), r)?)
+        }
+    } else if hr > hl + 1 {
+        let (rl, rr) = children(&r)?.unwrap();
+        if info(rr)?.height >= info(rl)?.height {
+            node(node(l, rl.clone())?, rr.clone())
+        } else {
+            let (rll, rlr) = children(rl)?.unwrap();
+            node(node(l, rll.clone())?, node(rlr.clone(), rr.clone())?)
+        }
+    } else {
+        node(l, r)
+    }
+}
+
+fn join(l: Object, r: Object) -> super::Result<Object> {
+    let (a, b) = (info(&l)?, info(&r)?);
+    if a.len == 0 {
+        return Ok(r);
+    } else if b.len == 0 {
+        return Ok(l);
+    } else if a.height == 0 && b.height == 0 && a.len + b.len <= LEAF_MAX {
+        return Ok(l.concat(&r));
+    }
+    if a.height > b.height + 1 {
+        let (ll, lr) = children(&l)?.unwrap();
+        balance(ll.clone(), join(lr.clone(), r)?)
+    } else if b.height > a.height + 1 {
+        let (rl, rr) = children(&r)?.unwrap();
+        balance(join(l, rl.clone())?, rr.clone())
+    } else {
+        node(l, r)
+    }
+}
+
+fn split(t: &Object, at: usize) -> super::Result<(Object, Object)> {
+    let Some((l, r)) = children(t)? else {
+        let n = t.data().len();
+        return Ok((t.slice(0..at, 0..0), t.slice(at..n, 0..0)));
+    };
+    let n = info(l)?.len;
+    if at <= n {
+        let (ll, lr) = split(l, at)?;
+        Ok((ll, join(lr, r.clone())?))
+    } else {
+        let (rl, rr) = split(r, at - n)?;
+        Ok((join(l.clone(), rl)?, rr))
+    }
+}
+
+/// Create a balanced text from bytes.
+fn new(bytes: &Object) -> super::Result<Object> {
+    let n = bytes.data().len();
+    if n <= LEAF_MAX {
+        return Ok(bytes.slice(0..n, 0..0));
+    }
+    let mid = n / 2;
+    node(
+        new(&bytes.slice(0..mid, 0..0))?,
+        new(&bytes.slice(mid..n, 0..0))?,
+    )
+}
+
+fn check(t: &Object, range: core::ops::Range<usize>) -> super::Result<()> {
+    let len = info(t)?.len;
+    if range.start > range.end || range.end > len {
+        return Err(format!("range {range:?} is out of bounds for text of length {len}").into());
+    }
+    Ok(())
+}
+
+fn bytes(t: &Object, out: &mut Vec<u8>) -> super::Result<()> {
+    match children(t)? {
+        Some((l, r)) => {
+            bytes(l, out)?;
+            bytes(r, out)
+        }
+        None => {
+            out.extend_from_slice(t.data());
+            Ok(())
+        }
+    }
+}
+
+/// Get the offset right after the `n`th newline.
+fn line_offset(t: &Object, n: usize) -> super::Result<usize> {
+    if n == 0 {
+        return Ok(0);
+    }
+    match children(t)? {
+        Some((l, r)) => {
+            let a = info(l)?;
+            if n <= a.newlines {
+                line_offset(l, n)
+            } else {
+                Ok(a.len + line_offset(r, n - a.newlines)?)
+            }
+        }
+        None => t
+            .data()
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| **x == b'\n')
+            .nth(n - 1)
+            .map(|(i, _)| i + 1)
+            .ok_or_else(|| format!("line {n} is out of bounds").into()),
+    }
+}
+
+/// Count the newlines before an offset.
+fn offset_line(t: &Object, at: usize) -> super::Result<usize> {
+    match children(t)? {
+        Some((l, r)) => {
+            let a = info(l)?;
+            if at <= a.len {
+                offset_line(l, at)
+            } else {
+                Ok(a.newlines + offset_line(r, at - a.len)?)
+            }
+        }
+        None => Ok(t.data()[..at].iter().filter(|x| **x == b'\n').count()),
+    }
+}
+
+pub fn define<F>(
+    comp: &Compiler,
+    dictionary: &Dictionary,
+    read_word: &Rc<F>,
+    int: &Rc<Stack<Int>>,
+    obj: &Rc<Stack<Object>>,
+) where
+    F: 'static + Fn() -> super::Result<Option<String>>,
+{
+    let usize = |int: &Stack<Int>| Ok::<_, super::Error>(usize::try_from(int.pop()?)?);
+    dictionary.dict(
+        "Text",
+        read_word,
+        &[
+            (
+                "new",
+                with_stacks(comp, int, obj, |_, obj| obj.push(new(&obj.pop()?)?)),
+            ),
+            (
+                "insert",
+                with_stacks(comp, int, obj, move |int, obj| {
+                    let at = usize(int)?;
+                    let x = obj.pop()?;
+                    let t = obj.pop()?;
+                    check(&t, at..at)?;
+                    let (l, r) = split(&t, at)?;
+                    obj.push(join(join(l, new(&x)?)?, r)?)
+                }),
+            ),
+            (
+                "delete",
+                with_stacks(comp, int, obj, move |int, obj| {
+                    let end = usize(int)?;
+                    let start = usize(int)?;
+                    let t = obj.pop()?;
+                    check(&t, start..end)?;
+                    let (l, r) = split(&t, start)?;
+                    let (_, r) = split(&r, end - start)?;
+                    obj.push(join(l, r)?)
+                }),
+            ),
+            (
+                "slice",
+                with_stacks(comp, int, obj, move |int, obj| {
+                    let end = usize(int)?;
+                    let start = usize(int)?;
+                    let t = obj.pop()?;
+                    check(&t, start..end)?;
+                    let (_, r) = split(&t, start)?;
+                    let (m, _) = split(&r, end - start)?;
+                    let mut v = vec![];
+                    bytes(&m, &mut v)?;
+                    obj.push(v.into())
+                }),
+            ),
+            (
+                "bytes",
+                with_stacks(comp, int, obj, |_, obj| {
+                    let mut v = vec![];
+                    bytes(&obj.pop()?, &mut v)?;
+                    obj.push(v.into())
+                }),
+            ),
+            (
+                "length",
+                with_stacks(comp, int, obj, |int, obj| {
+                    int.push(info(&obj.pop()?)?.len.into())
+                }),
+            ),
+            (
+                "lines",
+                with_stacks(comp, int, obj, |int, obj| {
+                    int.push((info(&obj.pop()?)?.newlines + 1).into())
+                }),
+            ),
+            (
+                "line-offset",
+                with_stacks(comp, int, obj, move |int, obj| {
+                    let n = usize(int)?;
+                    int.push(line_offset(&obj.pop()?, n)?.into())
+                }),
+            ),
+            (
+                "offset-line",
+                with_stacks(comp, int, obj, move |int, obj| {
+                    let at = usize(int)?;
+                    let t = obj.pop()?;
+                    check(&t, at..at)?;
+                    int.push(offset_line(&t, at)?.into())
+                }),
+            ),
+        ],
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &[u8]) -> Object {
+        new(&s.to_vec().into()).unwrap()
+    }
+
+    fn contents(t: &Object) -> Vec<u8> {
+        let mut v = vec![];
+        bytes(t, &mut v).unwrap();
+        v
+    }
+
+    fn insert(t: &Object, at: usize, s: &[u8]) -> Object {
+        let (l, r) = split(t, at).unwrap();
+        join(join(l, text(s)).unwrap(), r).unwrap()
+    }
+
+    fn delete(t: &Object, start: usize, end: usize) -> Object {
+        let (l, r) = split(t, start).unwrap();
+        let (_, r) = split(&r, end - start).unwrap();
+        join(l, r).unwrap()
+    }
+
+    fn sample() -> Vec<u8> {
+        (0..1000)
+            .flat_map(|i| format!("line {i}\n").into_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn edit() {
+        let mut expected = sample();
+        let mut t = text(&expected);
+        assert!(info(&t).unwrap().height > 2);
+        for i in 0..200 {
+            let at = i * 37 % expected.len();
+            t = insert(&t, at, b"x\ny");
+            expected.splice(at..at, *b"x\ny");
+            let end = (at + i % 50).min(expected.len());
+            t = delete(&t, at, end);
+            expected.drain(at..end);
+        }
+        assert_eq!(contents(&t), expected);
+        let a = info(&t).unwrap();
+        assert_eq!(a.len, expected.len());
+        assert_eq!(a.newlines, expected.iter().filter(|x| **x == b'\n').count());
+        // balanced
+        assert!(a.height <= 2 * (a.len / LEAF_MAX + 1).ilog2() as usize + 2);
+    }
+
+    #[test]
+    fn lines() {
+        let s = sample();
+        let t = insert(&text(&s), 5000, &[b'-'; 3000]);
+        let mut expected = s;
+        expected.splice(5000..5000, [b'-'; 3000]);
+        let offsets = core::iter::once(0)
+            .chain(
+                expected
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, x)| **x == b'\n')
+                    .map(|(i, _)| i + 1),
+            )
+            .collect::<Vec<_>>();
+        for (n, at) in offsets.iter().enumerate() {
+            assert_eq!(line_offset(&t, n).unwrap(), *at);
+            assert_eq!(offset_line(&t, *at).unwrap(), n);
+        }
+        assert!(line_offset(&t, offsets.len()).is_err());
+    }
+
+    #[test]
+    fn invalid_header() {
+        let t = text(&sample());
+        let (l, r) = children(&t).unwrap().unwrap();
+        let mut header = t.data().to_vec();
+        header[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+        let forged = Object::new(header.into(), [l.clone(), r.clone()].into());
+        let e = check(&forged, 0..0).unwrap_err();
+        assert_eq!(e.to_string(), "object is not a text");
+        // a forged child under the original header
+        let mut header = l.data().to_vec();
+        header[..8].copy_from_slice(&(info(l).unwrap().len as u64 + 100).to_le_bytes());
+        let l = Object::new(header.into(), l.refs().into());
+        let forged = Object::new(t.data().into(), [l, r.clone()].into());
+        assert!(offset_line(&forged, info(&t).unwrap().len).is_err());
+        assert!(split(&forged, 10).is_err());
+    }
+}
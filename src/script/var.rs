@@ -1,4 +1,8 @@
-use super::{BigInt, Compiler, Dictionary, Object, Stack, Word, with_imm};
+use super::{
+    Compiler, Dictionary, Int, Object, Stack, Word,
+    image::{self, Persist, Reader},
+    with_imm,
+};
 use core::cell::Cell;
 use std::rc::Rc;
 
@@ -6,42 +10,212 @@ pub fn define<F>(
     comp: &Compiler,
     read_word: &Rc<F>,
     d: &Dictionary,
-    int: &Rc<Stack<BigInt>>,
+    int: &Rc<Stack<Int>>,
     obj: &Rc<Stack<Object>>,
 ) where
     F: 'static + Clone + Fn() -> super::Result<Option<String>>,
 {
-    fn f<T, F>(comp: &Compiler, d: &Dictionary, read_word: &Rc<F>, stack: &Rc<Stack<T>>) -> Word
+    fn f<T, F>(
+        comp: &Compiler,
+        d: &Dictionary,
+        read_word: &Rc<F>,
+        stack: &Rc<Stack<T>>,
+        kind: &'static str,
+    ) -> Word
     where
         F: 'static + Fn() -> super::Result<Option<String>>,
-        T: 'static + Default + Clone,
+        T: 'static + Default + Clone + Persist,
     {
+        let (c, d2, s) = (comp.clone(), d.clone(), stack.clone());
+        comp.loader(kind, move |name, r: &mut Reader| {
+            create(&c, &d2, &s, kind, name).set(T::load(r)?);
+            Ok(())
+        });
+        let comp = comp.clone();
+        let read_word = read_word.clone();
+        let s = stack.clone();
+        let d = d.clone();
+        with_imm(move || {
+            let name = read_word()?.unwrap();
+            create(&comp, &d, &s, kind, &name);
+            Ok(())
+        })
+    }
+    fn g<T, F>(
+        comp: &Compiler,
+        d: &Dictionary,
+        read_word: &Rc<F>,
+        stack: &Rc<Stack<T>>,
+        kind: &'static str,
+        literal: fn(&Compiler, T) -> Word,
+    ) -> Word
+    where
+        F: 'static + Fn() -> super::Result<Option<String>>,
+        T: 'static + Clone + Persist,
+    {
+        let (c, d2) = (comp.clone(), d.clone());
+        comp.loader(kind, move |name, r: &mut Reader| {
+            create_const(&c, &d2, kind, name, T::load(r)?, literal);
+            Ok(())
+        });
         let comp = comp.clone();
         let read_word = read_word.clone();
         let s = stack.clone();
         let d = d.clone();
         with_imm(move || {
             let name = read_word()?.unwrap();
-            let x = Rc::new(Cell::new(T::default()));
-            let x2 = x.clone();
-            let s = s.clone();
-            let s2 = s.clone();
-            d.define(
-                &name,
-                comp.with(move || {
-                    let x = x2.take();
-                    x2.set(x.clone());
-                    s2.push(x)
-                }),
-            );
-            d.define(
-                &format!("set:{name}"),
-                comp.with(move || s.pop().map(|v| x.set(v))),
-            );
+            create_const(&comp, &d, kind, &name, s.pop()?, literal);
             Ok(())
         })
     }
-    let int = ("integer", f(comp, d, read_word, int));
-    let obj = ("object", f(comp, d, read_word, obj));
-    d.dict("Var", read_word, &[int, obj]);
+    let (c, d2, o) = (comp.clone(), d.clone(), obj.clone());
+    comp.loader("Var word", move |name, r: &mut Reader| {
+        let x = create_word(&c, &d2, &o, name);
+        if r.usize()? != 0 {
+            // the word may be defined by a later entry
+            x.set(Held::Saved(r.rest().into()));
+        }
+        Ok(())
+    });
+    let word = {
+        let (c, d2, o, read_word) = (comp.clone(), d.clone(), obj.clone(), read_word.clone());
+        with_imm(move || {
+            let name = read_word()?.unwrap();
+            create_word(&c, &d2, &o, &name);
+            Ok(())
+        })
+    };
+    let int_c = g(comp, d, read_word, int, "Const integer", Compiler::int);
+    let obj_c = g(comp, d, read_word, obj, "Const object", Compiler::obj);
+    let int = ("integer", f(comp, d, read_word, int, "Var integer"));
+    let obj = ("object", f(comp, d, read_word, obj, "Var object"));
+    d.dict("Var", read_word, &[int, obj, ("word", word)]);
+    d.dict("Const", read_word, &[("integer", int_c), ("object", obj_c)]);
+}
+
+/// Define the words of a variable.
+fn create<T>(
+    comp: &Compiler,
+    d: &Dictionary,
+    s: &Rc<Stack<T>>,
+    kind: &str,
+    name: &str,
+) -> Rc<Cell<T>>
+where
+    T: 'static + Default + Clone + Persist,
+{
+    let x = Rc::new(Cell::new(T::default()));
+    let x2 = x.clone();
+    let s = s.clone();
+    let s2 = s.clone();
+    let get = comp.with(move || {
+        let x = x2.take();
+        x2.set(x.clone());
+        s2.push(x)
+    });
+    let (x2, c) = (x.clone(), comp.clone());
+    let set = comp.with(move || {
+        let v = s.pop()?;
+        touch(&c, &x2);
+        x2.set(v);
+        Ok(())
+    });
+    let x2 = x.clone();
+    comp.log(kind, name, &[get.clone(), set.clone()], move |w| {
+        let v = x2.take();
+        v.save(w);
+        x2.set(v);
+        Ok(())
+    });
+    d.define(name, get);
+    d.define(&format!("set:{name}"), set);
+    x
+}
+
+/// Define a constant, which is compiled as a literal.
+fn create_const<T>(
+    comp: &Compiler,
+    d: &Dictionary,
+    kind: &str,
+    name: &str,
+    value: T,
+    literal: fn(&Compiler, T) -> Word,
+) where
+    T: 'static + Clone + Persist,
+{
+    let get = (literal)(comp, value.clone());
+    comp.log(kind, name, core::slice::from_ref(&get), move |w| {
+        value.save(w);
+        Ok(())
+    });
+    d.define(name, get);
+}
+
+/// Restore a variable if the running transaction fails.
+fn touch<T>(comp: &Compiler, x: &Rc<Cell<T>>)
+where
+    T: 'static + Default + Clone,
+{
+    comp.touch(Rc::as_ptr(x).cast(), || {
+        let (x, v) = (x.clone(), x.take());
+        x.set(v.clone());
+        move || x.set(v)
+    })
+}
+
+/// The word held by a variable.
+#[derive(Clone, Default)]
+enum Held {
+    #[default]
+    None,
+    Word(Word),
+    /// Loaded from an image, but not yet resolved.
+    Saved(Box<[u8]>),
+}
+
+/// Define a variable holding a word.
+///
+/// The variable runs the word and `set:` takes the execution token of the word to hold.
+fn create_word(
+    comp: &Compiler,
+    d: &Dictionary,
+    obj: &Rc<Stack<Object>>,
+    name: &str,
+) -> Rc<Cell<Held>> {
+    let x = Rc::new(Cell::new(Held::None));
+    let get_word = {
+        let (x, c, d) = (x.clone(), comp.clone(), d.clone());
+        move || {
+            let w = match x.take() {
+                Held::None => None,
+                Held::Word(w) => Some(w),
+                Held::Saved(data) => match image::read_word(&data, &c, &d) {
+                    Ok((w, _)) => Some(w),
+                    Err(e) => {
+                        x.set(Held::Saved(data));
+                        return Err(e);
+                    }
+                },
+            };
+            x.set(w.clone().map_or(Held::None, Held::Word));
+            Ok::<_, super::Error>(w)
+        }
+    };
+    let (f, name2) = (get_word.clone(), Box::<str>::from(name));
+    let get = comp.with(move || (f()?.ok_or_else(|| format!("{name2} is not set"))?)());
+    let (x2, o, c) = (x.clone(), obj.clone(), comp.clone());
+    let set = comp.with(move || {
+        let w = c.token_word(&o.pop()?)?;
+        touch(&c, &x2);
+        x2.set(Held::Word(w));
+        Ok(())
+    });
+    comp.log("Var word", name, &[get.clone(), set.clone()], move |w| {
+        let v = get_word()?;
+        w.usize(v.is_some().into());
+        v.map_or(Ok(()), |v| w.word(&v))
+    });
+    d.define(name, get);
+    d.define(&format!("set:{name}"), set);
+    x
 }